@@ -1,12 +1,23 @@
-use clap::{arg, value_parser, ArgAction, ArgMatches, Command};
+use clap::{arg, value_parser, Arg, ArgAction, ArgMatches, Command};
+use sognasm::Limits;
+use std::path::PathBuf;
+use std::time::Duration;
 
 pub struct Arguments(ArgMatches);
 
+// -- 之后的参数原样交给程序
+fn program_args() -> Arg {
+    arg!([args] "传给程序的参数, 写在--之后")
+        .num_args(0..)
+        .last(true)
+}
+
 fn argus() -> ArgMatches {
     Command::new("Sognasm")
         .version("v0.2.0")
         .about("Sognac的字节码解释器")
         .arg(arg!([source]).required(true))
+        .arg(program_args())
         .arg(arg!(-p --print "打印运行栈").action(ArgAction::SetTrue).required(false))
         .arg(
             arg!(-s --speed <speed> "打印周期(单位:ms)")
@@ -14,7 +25,103 @@ fn argus() -> ArgMatches {
                 .default_value("100"),
         )
         .arg(arg!(-c --code "打印字节码").action(ArgAction::SetTrue).required(false))
-        // .arg(arg!(-o --output <file> ).required(false))
+        .arg(
+            arg!(-d --disassemble "反汇编成可以重新汇编的源码")
+                .action(ArgAction::SetTrue)
+                .required(false),
+        )
+        .arg(
+            arg!(-O --optimize "常量折叠和窥孔优化")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .required(false),
+        )
+        .arg(arg!(-o --output <file> "只编译成字节码, 不运行").required(false))
+        .arg(
+            arg!(-I --include <dir> "Import的搜索路径, 可以指定多个")
+                .value_parser(value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--"max-stack" <n> "栈上最多能放的值")
+                .value_parser(value_parser!(usize))
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--"max-depth" <n> "最深的调用层数")
+                .value_parser(value_parser!(usize))
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--"max-locals" <n> "局部变量的总数上限")
+                .value_parser(value_parser!(usize))
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--"max-list-len" <n> "列表最长的长度")
+                .value_parser(value_parser!(usize))
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--fuel <n> "最多执行的指令数")
+                .value_parser(value_parser!(u64))
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--timeout <ms> "运行时间上限(单位:ms)")
+                .value_parser(value_parser!(u64))
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--seed <n> "随机数的种子, 相同的种子得到相同的结果")
+                .value_parser(value_parser!(u64))
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--sandbox "不允许程序读写文件和读取环境变量")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            arg!(--profile "统计每种指令和每个函数的执行情况")
+                .action(ArgAction::SetTrue)
+                // 这些模式不会正常运行程序, 统计不到东西
+                .conflicts_with_all(["print", "code", "output", "disassemble"])
+                .required(false),
+        )
+        .arg(
+            arg!(--folded <file> "把折叠的调用栈写到文件里, 用来画火焰图")
+                .requires("profile")
+                .required(false),
+        )
+        .arg(
+            arg!(--coverage <file> "把覆盖率写成lcov格式的文件")
+                .conflicts_with_all(["profile", "print", "code", "output", "disassemble"])
+                .required(false),
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("在调试器里运行")
+                .arg(arg!(<source>))
+                .arg(program_args()),
+        )
+        .subcommand(
+            Command::new("repl")
+                .about("逐行执行, 保留栈和定义过的函数")
+                .arg(program_args()),
+        )
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
         .get_matches()
 }
 
@@ -38,12 +145,91 @@ impl Arguments {
         *self.0.get_one("code").unwrap()
     }
 
+    pub fn is_disassemble(&self) -> bool {
+        *self.0.get_one("disassemble").unwrap()
+    }
+
+    pub fn is_optimize(&self) -> bool {
+        *self.matches().get_one("optimize").unwrap()
+    }
+
+    // 子命令有自己的参数
+    fn matches(&self) -> &ArgMatches {
+        match self.0.subcommand() {
+            Some((_, matches)) => matches,
+            None => &self.0,
+        }
+    }
+
+    pub fn is_debug(&self) -> bool {
+        matches!(self.0.subcommand(), Some(("debug", _)))
+    }
+
+    pub fn is_repl(&self) -> bool {
+        matches!(self.0.subcommand(), Some(("repl", _)))
+    }
+
     pub fn source(&self) -> &String {
-        self.0.get_one("source").unwrap()
+        self.matches().get_one("source").unwrap()
     }
 
-    #[allow(dead_code)]
-    pub fn output(&self) -> &String {
-        self.0.get_one("output").unwrap()
+    pub fn is_sandbox(&self) -> bool {
+        *self.matches().get_one("sandbox").unwrap()
+    }
+
+    pub fn is_profile(&self) -> bool {
+        *self.0.get_one("profile").unwrap()
+    }
+
+    pub fn folded(&self) -> Option<&String> {
+        self.0.get_one("folded")
+    }
+
+    pub fn coverage(&self) -> Option<&String> {
+        self.0.get_one("coverage")
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        self.matches()
+            .get_many("args")
+            .map(|args| args.cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn output(&self) -> Option<&String> {
+        self.0.get_one("output")
+    }
+
+    // 没有指定的限制用默认值
+    pub fn limits(&self) -> Limits {
+        let limit = |name: &str, default: usize| *self.matches().get_one(name).unwrap_or(&default);
+        let default = Limits::default();
+        Limits {
+            max_stack: limit("max-stack", default.max_stack),
+            max_depth: limit("max-depth", default.max_depth),
+            max_locals: limit("max-locals", default.max_locals),
+            max_list_len: limit("max-list-len", default.max_list_len),
+        }
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.matches().get_one("fuel").copied()
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.matches()
+            .get_one("timeout")
+            .map(|ms| Duration::from_millis(*ms))
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.matches().get_one("seed").copied()
+    }
+
+    pub fn include(&self) -> Vec<PathBuf> {
+        self.matches()
+            .get_many("include")
+            .map(|dirs| dirs.cloned().collect())
+            .unwrap_or_default()
     }
 }
@@ -2,15 +2,28 @@ use std::collections::HashMap;
 
 use crate::command::Cmd;
 use crate::command::Oper;
+use crate::debug::DebugInfo;
+use crate::error::DecodeError;
 use crate::parser::map_color;
 use crate::parser::Number;
 
+#[derive(Debug, Clone)]
+pub enum Operand {
+    Empty,
+    Byte(u8),
+    Offset(usize),
+    List(Vec<usize>),
+}
+
 #[derive(Debug, Clone)]
 pub struct Asm {
     pub cmds: Vec<Cmd>,
     pub string_pool: Vec<String>,
     pub number_pool: Vec<Number>,
     pub function_pool: Vec<usize>,
+    // Sys指令用到的系统调用名
+    pub syscall_pool: Vec<String>,
+    pub debug: Option<DebugInfo>,
 }
 
 impl Asm {
@@ -25,6 +38,8 @@ impl Asm {
             string_pool,
             number_pool,
             function_pool,
+            syscall_pool: Vec::new(),
+            debug: None,
         }
     }
 
@@ -37,23 +52,90 @@ impl Asm {
         self.cmds[index].0
     }
 
+    // LEB128变长整数, 返回值和下一个字节的位置
     pub fn offset(&self, mut index: usize) -> (usize, usize) {
         let mut offset = 0;
+        let mut shift = 0;
         loop {
             let byte = self.cmds[index].0;
             index += 1;
-            offset += byte;
-            if byte != 0xff {
+            offset |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
                 break;
             }
+            shift += 7;
         }
-        (offset as usize, index)
+        (offset, index)
     }
 
-    pub fn list(&self, index: usize) -> (Vec<Cmd>, usize) {
-        let length = self.byte(index) as usize;
-        let list = self.cmds[index + 1..index + length + 1].to_vec();
-        (list, index + length + 1)
+    pub fn list(&self, index: usize) -> (Vec<usize>, usize) {
+        let (length, mut index) = self.offset(index);
+        let mut list = Vec::with_capacity(length);
+        for _ in 0..length {
+            let (offset, next) = self.offset(index);
+            list.push(offset);
+            index = next;
+        }
+        (list, index)
+    }
+
+    fn checked_offset(&self, mut index: usize) -> Result<(usize, usize), DecodeError> {
+        let start = index;
+        let mut offset = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = self.cmds.get(index).ok_or(DecodeError::Truncated(start))?.0;
+            index += 1;
+            let bits = ((byte & 0x7f) as usize)
+                .checked_shl(shift)
+                .filter(|bits| bits >> shift == (byte & 0x7f) as usize)
+                .ok_or(DecodeError::Overflow(start))?;
+            offset |= bits;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        Ok((offset, index))
+    }
+
+    // 带边界检查的解码, 返回操作符, 操作数和下一条指令的位置
+    pub fn decode(&self, index: usize) -> Result<(Oper, Operand, usize), DecodeError> {
+        use Oper::*;
+        let byte = |index: usize| {
+            self.cmds
+                .get(index)
+                .map(|cmd| cmd.0)
+                .ok_or(DecodeError::Truncated(index))
+        };
+        let oper = Oper::from(&Cmd(byte(index)?));
+        let mut next = index + 1;
+        let operand = match oper {
+            Oper::__ => return Err(DecodeError::UnknownOper(index, byte(index)?)),
+            Byte => {
+                next += 1;
+                Operand::Byte(byte(index + 1)?)
+            }
+            Local | Push | Capped | PushCap | Call | Func | Num | Str | TailCall | TailLocal
+            | TailCapped | Sys => {
+                let (offset, after) = self.checked_offset(next)?;
+                next = after;
+                Operand::Offset(offset)
+            }
+            Capture | CapCap => {
+                let (length, mut after) = self.checked_offset(next)?;
+                let mut list = Vec::new();
+                for _ in 0..length {
+                    let (offset, index) = self.checked_offset(after)?;
+                    list.push(offset);
+                    after = index;
+                }
+                next = after;
+                Operand::List(list)
+            }
+            _ => Operand::Empty,
+        };
+        Ok((oper, operand, next))
     }
 
     pub fn display(&self, index: usize, labels: &[String]) {
@@ -86,20 +168,25 @@ impl Asm {
             }
             print!("{}m{:?}\x1b[1m", map_color(&oper), oper);
             match oper {
-                Local | Push | Capped | PushCap => {
-                    let byte = self.byte(cur_index);
-                    cur_index += 1;
-                    print!(" {}", byte);
+                Local | Push | Capped | PushCap | TailLocal | TailCapped => {
+                    let (offset, index) = self.offset(cur_index);
+                    cur_index = index;
+                    print!(" {}", offset);
                 }
-                Call | Func => {
+                Call | Func | TailCall => {
                     let (offset, index) = self.offset(cur_index);
                     cur_index = index;
                     print!(" {}", labels[offset]);
                 }
+                Sys => {
+                    let (offset, index) = self.offset(cur_index);
+                    cur_index = index;
+                    print!(" {}", self.syscall_pool[offset]);
+                }
                 Capture | CapCap => {
                     let (list, index) = self.list(cur_index);
                     cur_index = index;
-                    print!("{:?}", list.iter().map(|cmd| cmd.0).collect::<Vec<u8>>())
+                    print!("{:?}", list)
                 }
                 Byte => {
                     let byte = self.byte(cur_index);
@@ -120,10 +207,10 @@ impl Asm {
                     let (offset, index) = self.offset(cur_index);
                     cur_index = index;
                     let str = &self.string_pool[offset];
-                    let str = if str.len() > 5 {
-                        format!("{}..", &str[..5])
-                    } else {
-                        str.to_owned()
+                    // 按字符截断, 中文不能从中间切开
+                    let str = match str.char_indices().nth(5) {
+                        Some((end, _)) => format!("{}..", &str[..end]),
+                        None => str.to_owned(),
                     };
                     print!(" \"{}\"", str);
                 }
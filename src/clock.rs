@@ -0,0 +1,29 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::ErrorMessage;
+use crate::syscall::Syscalls;
+use crate::value::Value;
+
+// 时间都以秒为单位, 可以有小数
+pub(crate) fn register(syscalls: &mut Syscalls) {
+    // 1970年以来的秒数, 系统时间被调整时可能会倒退
+    syscalls.register("time.now", |machine| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        machine.push(Value::Number(now.as_secs_f64()))
+    });
+
+    // 从注册开始计算, 不会倒退, 适合计算间隔
+    let start = Instant::now();
+    syscalls.register("time.clock", move |machine| {
+        machine.push(Value::Number(start.elapsed().as_secs_f64()))
+    });
+
+    syscalls.register("time.sleep", |machine| {
+        let seconds = machine.pop()?.into_number();
+        let duration = Duration::try_from_secs_f64(seconds)
+            .map_err(|_| ErrorMessage::Syscall(format!("不能睡眠{}秒", seconds)))?;
+        machine.sleep(duration)
+    });
+}
@@ -65,6 +65,16 @@ pub enum Oper {
     False,
 
     End,
+
+    // 尾调用, 复用当前的栈帧, 后面仍然跟着Ret
+    TailCall,   // 同Call
+    TailLocal,  // 同Local
+    TailCapped, // 同Capped
+
+    Sys, // 后接一个usize, 按名字调用宿主注册的系统调用
+
+    Try,   // 调用栈顶的函数, 它出错时回到这里调用下一个函数
+    Throw, // 把栈顶的值作为异常抛出
 }
 
 #[derive(Copy, Clone)]
@@ -150,6 +160,15 @@ impl From<&Cmd> for Oper {
 
             51 => End,
 
+            52 => TailCall,
+            53 => TailLocal,
+            54 => TailCapped,
+
+            55 => Sys,
+
+            56 => Try,
+            57 => Throw,
+
             _ => __,
         }
     }
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::assemble::Asm;
+use crate::command::Oper;
+use crate::runtime::Runtime;
+
+// 每条指令执行的次数, 在Runtime::execute的trace里收集
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    // 下标是字节码位置, 只有指令的起始位置会被记录
+    pub hits: Vec<u64>,
+    // 每个If指令的位置, 以及条件为真和为假的次数
+    pub branches: BTreeMap<usize, (u64, u64)>,
+}
+
+// 一个函数从它的入口开始, 到下一个函数的入口为止
+struct Function {
+    name: String,
+    start: usize,
+    end: usize,
+}
+
+// 只统计能解码的指令, 运行之前已经检查过字节码了
+// 尾调用后面留下的Ret通常执行不到, 不算在里面
+fn instructions(asm: &Asm) -> Vec<(usize, Oper)> {
+    use Oper::*;
+    let mut instructions = Vec::new();
+    let mut index = 0;
+    let mut tail = false;
+    while let Ok((oper, _, next)) = asm.decode(index) {
+        if !(tail && matches!(oper, Ret)) {
+            instructions.push((index, oper));
+        }
+        tail = matches!(oper, TailCall | TailLocal | TailCapped);
+        index = next;
+    }
+    instructions
+}
+
+fn functions(asm: &Asm) -> Vec<Function> {
+    let mut entries: Vec<usize> = asm.function_pool.clone();
+    entries.sort_unstable();
+    entries.dedup();
+    if entries.first() != Some(&0) {
+        entries.insert(0, 0);
+    }
+    let mut functions = Vec::new();
+    for (index, start) in entries.iter().enumerate() {
+        let name = match asm.label_name(*start) {
+            Some(name) => name.to_owned(),
+            None if *start == 0 => "<main>".to_owned(),
+            None => asm.function_name(*start),
+        };
+        let end = entries.get(index + 1).copied().unwrap_or(asm.cmds.len());
+        functions.push(Function {
+            name,
+            start: *start,
+            end,
+        });
+    }
+    functions
+}
+
+impl Coverage {
+    pub fn new(asm: &Asm) -> Self {
+        Coverage {
+            hits: vec![0; asm.cmds.len()],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    // 在执行oper之前调用, 这时If的条件还在栈顶
+    pub fn step(&mut self, runtime: &Runtime, oper: Oper) {
+        let index = runtime.current();
+        self.hits[index] += 1;
+        if let Oper::If = oper {
+            let Some(cond) = runtime.machine().stack.last() else {
+                return;
+            };
+            let branch = self.branches.entry(index).or_default();
+            if cond.clone().into_bool() {
+                branch.0 += 1;
+            } else {
+                branch.1 += 1;
+            }
+        }
+    }
+
+    // lcov格式, 需要调试信息才能找到源码中的行
+    pub fn lcov(&self, asm: &Asm) -> Option<String> {
+        let debug = asm.debug.as_ref()?;
+        let instructions = instructions(asm);
+        let functions = functions(asm);
+        let mut out = String::new();
+        for (file, path) in debug.files.iter().enumerate() {
+            let line = |index: usize| {
+                debug
+                    .location(index)
+                    .filter(|location| location.file == file)
+                    .map(|location| location.line)
+            };
+            let _ = writeln!(out, "TN:\nSF:{}", path);
+
+            let mut found = 0;
+            let mut hit = 0;
+            for function in &functions {
+                let Some(line) = line(function.start) else {
+                    continue;
+                };
+                let _ = writeln!(out, "FN:{},{}", line, function.name);
+                let _ = writeln!(out, "FNDA:{},{}", self.hits[function.start], function.name);
+                found += 1;
+                hit += (self.hits[function.start] > 0) as usize;
+            }
+            let _ = writeln!(out, "FNF:{}\nFNH:{}", found, hit);
+
+            // 分支0是条件为真, 分支1是条件为假, 没执行过的If记为-
+            let (mut found, mut hit) = (0, 0);
+            for (block, (index, _)) in instructions
+                .iter()
+                .filter(|(_, oper)| matches!(oper, Oper::If))
+                .enumerate()
+            {
+                let Some(line) = line(*index) else {
+                    continue;
+                };
+                let branch = self.branches.get(index);
+                for (number, taken) in [branch.map(|x| x.0), branch.map(|x| x.1)]
+                    .into_iter()
+                    .enumerate()
+                {
+                    let taken = taken.map_or("-".to_owned(), |x| x.to_string());
+                    let _ = writeln!(out, "BRDA:{},{},{},{}", line, block, number, taken);
+                    found += 1;
+                    hit += (taken != "-" && taken != "0") as usize;
+                }
+            }
+            let _ = writeln!(out, "BRF:{}\nBRH:{}", found, hit);
+
+            // 同一行有多条指令时取执行次数最多的
+            let mut lines = BTreeMap::<usize, u64>::new();
+            for (index, _) in &instructions {
+                if let Some(line) = line(*index) {
+                    let count = lines.entry(line).or_default();
+                    *count = (*count).max(self.hits[*index]);
+                }
+            }
+            for (line, count) in &lines {
+                let _ = writeln!(out, "DA:{},{}", line, count);
+            }
+            let hit = lines.values().filter(|count| **count > 0).count();
+            let _ = writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines.len(), hit);
+        }
+        Some(out)
+    }
+
+    // 每个函数执行过的指令数和If的分支数
+    pub fn summary(&self, asm: &Asm) -> String {
+        let instructions = instructions(asm);
+        let mut out = String::new();
+        let (mut total, mut covered) = (0, 0);
+        for function in functions(asm) {
+            let start = instructions.partition_point(|(index, _)| *index < function.start);
+            let end = instructions.partition_point(|(index, _)| *index < function.end);
+            let inside = &instructions[start..end];
+            let hit = inside
+                .iter()
+                .filter(|(index, _)| self.hits[*index] > 0)
+                .count();
+            let (mut branches, mut taken) = (0, 0);
+            for (index, oper) in inside {
+                if let Oper::If = oper {
+                    let branch = self.branches.get(index).copied().unwrap_or_default();
+                    branches += 2;
+                    taken += (branch.0 > 0) as usize + (branch.1 > 0) as usize;
+                }
+            }
+            let _ = write!(
+                out,
+                "{:<20} {:>5}/{:<5} {:>5.1}%",
+                function.name,
+                hit,
+                inside.len(),
+                percent(hit, inside.len())
+            );
+            if branches > 0 {
+                let _ = write!(out, "  分支 {}/{}", taken, branches);
+            }
+            out.push('\n');
+            total += inside.len();
+            covered += hit;
+        }
+        let _ = writeln!(
+            out,
+            "指令覆盖率 {}/{} {:.1}%",
+            covered,
+            total,
+            percent(covered, total)
+        );
+        out
+    }
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
@@ -0,0 +1,69 @@
+use crate::assemble::Asm;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+// 调试信息: 字节码的每个字节都对应一个源码位置
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub lines: Vec<Location>,
+    // 函数池里每个函数的标签名, 和function_pool一一对应
+    pub labels: Vec<String>,
+}
+
+impl DebugInfo {
+    pub fn location(&self, index: usize) -> Option<&Location> {
+        self.lines.get(index)
+    }
+
+    pub fn format(&self, location: &Location) -> String {
+        let file = self.files.get(location.file).map_or("?", |x| x.as_str());
+        format!("{}:{}:{}", file, location.line, location.column)
+    }
+}
+
+// 记录每一行的起始位置, 用二分查找把字节偏移换成行列号
+pub struct LineIndex<'a> {
+    input: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(input: &'a str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(input.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        LineIndex { input, starts }
+    }
+
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|start| *start <= offset) - 1;
+        let column = self.input[self.starts[line]..offset].chars().count();
+        (line + 1, column + 1)
+    }
+}
+
+impl Asm {
+    pub fn source_location(&self, index: usize) -> Option<String> {
+        let debug = self.debug.as_ref()?;
+        debug.location(index).map(|location| debug.format(location))
+    }
+
+    // 从函数入口地址找到它的标签名
+    pub fn label_name(&self, ip: usize) -> Option<&str> {
+        let debug = self.debug.as_ref()?;
+        let func = self.function_pool.iter().position(|x| *x == ip)?;
+        debug.labels.get(func).map(String::as_str)
+    }
+
+    // 没有标签名时用函数地址代替
+    pub fn function_name(&self, ip: usize) -> String {
+        self.label_name(ip)
+            .map_or_else(|| format!("F{}", ip), str::to_owned)
+    }
+}
@@ -0,0 +1,201 @@
+use colored::Colorize;
+use sognasm::{ErrorMessage, ExitStatus, Runtime, RuntimeError};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+break <标签|位置>   在函数入口或者字节码位置设置断点 (b)
+delete [标签|位置]  删除断点, 不带参数时删除所有断点 (d)
+step               执行一条指令 (s)
+next               执行一条指令, 不进入函数调用 (n)
+finish             执行到当前函数返回 (f)
+continue           执行到下一个断点 (c)
+print [$n|#n]      打印栈和局部变量, 或者某个局部变量/捕获变量 (p)
+backtrace          打印调用栈 (bt)
+list               打印字节码, 高亮下一条指令 (l)
+quit               退出 (q)";
+
+pub struct Debugger<'a> {
+    runtime: Runtime<'a>,
+    labels: Vec<String>,
+    breakpoints: BTreeSet<usize>,
+    // 程序结束之后只能查看状态, 不能继续执行
+    result: Option<Result<ExitStatus, RuntimeError>>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(runtime: Runtime<'a>, labels: Vec<String>) -> Self {
+        Debugger {
+            runtime,
+            labels,
+            breakpoints: BTreeSet::new(),
+            result: None,
+        }
+    }
+
+    // 读取命令直到退出, 返回程序的运行结果, 程序还没结束时返回None
+    pub fn run(mut self) -> Option<Result<ExitStatus, RuntimeError>> {
+        self.show_position();
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(sdb) ");
+            io::stdout().flush().ok();
+            let Some(Ok(line)) = lines.next() else {
+                break;
+            };
+            let mut words = line.split_whitespace();
+            let (Some(command), argument) = (words.next(), words.next()) else {
+                continue;
+            };
+            match command {
+                "break" | "b" => self.set_breakpoint(argument),
+                "delete" | "d" => self.delete_breakpoint(argument),
+                "step" | "s" => self.resume(|_| true),
+                "next" | "n" => {
+                    let depth = self.depth();
+                    self.resume(|runtime| runtime.machine().frames.len() <= depth)
+                }
+                "finish" | "f" => {
+                    let depth = self.depth();
+                    self.resume(|runtime| runtime.machine().frames.len() < depth)
+                }
+                "continue" | "c" => self.resume(|_| false),
+                "print" | "p" => self.print(argument),
+                "backtrace" | "bt" => self.backtrace(),
+                "list" | "l" => {
+                    let asm = self.runtime.asm();
+                    asm.display(self.runtime.index() + 1, &self.labels);
+                    println!("\x1b[0m");
+                }
+                "help" | "h" => println!("{}", HELP),
+                "quit" | "q" => break,
+                _ => println!("不认识的命令 {}, 输入help查看帮助", command),
+            }
+        }
+        self.result
+    }
+
+    fn depth(&self) -> usize {
+        self.runtime.machine().frames.len()
+    }
+
+    // 标签或者字节码位置
+    fn resolve(&self, argument: &str) -> Option<usize> {
+        let asm = self.runtime.asm();
+        let index = match argument.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => {
+                let func = self.labels.iter().position(|label| label == argument)?;
+                asm.function_pool[func]
+            }
+        };
+        (index < asm.cmds.len()).then_some(index)
+    }
+
+    fn set_breakpoint(&mut self, argument: Option<&str>) {
+        let Some(index) = argument.and_then(|x| self.resolve(x)) else {
+            return println!("找不到断点位置 {}", argument.unwrap_or(""));
+        };
+        self.breakpoints.insert(index);
+        println!("断点设置在 {}", self.location(index));
+    }
+
+    fn delete_breakpoint(&mut self, argument: Option<&str>) {
+        match argument {
+            None => self.breakpoints.clear(),
+            Some(argument) => match self.resolve(argument) {
+                Some(index) if self.breakpoints.remove(&index) => {}
+                _ => println!("这里没有断点 {}", argument),
+            },
+        }
+    }
+
+    // 至少执行一条指令, 然后执行到断点, 中断或者stop返回true为止
+    fn resume<T>(&mut self, mut stop: T)
+    where
+        T: FnMut(&Runtime<'a>) -> bool,
+    {
+        if self.result.is_some() {
+            return println!("程序已经结束了");
+        }
+        loop {
+            match self.runtime.step() {
+                Ok(None) => {}
+                Ok(Some(status)) => {
+                    println!("程序结束, 栈: {:?}", status.stack);
+                    self.result = Some(Ok(status));
+                    return;
+                }
+                Err(err) if matches!(err.message, ErrorMessage::Interrupted) => break,
+                Err(err) => {
+                    crate::report(&err);
+                    self.result = Some(Err(err));
+                    return;
+                }
+            }
+            if self.breakpoints.contains(&self.runtime.index()) {
+                println!("{}", "断点".yellow());
+                break;
+            }
+            if stop(&self.runtime) {
+                break;
+            }
+        }
+        self.show_position();
+    }
+
+    fn show_position(&self) {
+        let index = self.runtime.index();
+        let oper = self.runtime.asm().oper(index);
+        println!("下一条: {:?} 在 {}", oper, self.location(index));
+    }
+
+    fn location(&self, index: usize) -> String {
+        self.runtime
+            .asm()
+            .source_location(index)
+            .unwrap_or_else(|| format!("@{}", index))
+    }
+
+    fn print(&self, argument: Option<&str>) {
+        let machine = self.runtime.machine();
+        let Some(argument) = argument else {
+            return println!("{}", machine);
+        };
+        // 按第一个字符切开, 参数可能以中文开头
+        let first = argument.chars().next().map_or(0, char::len_utf8);
+        let value = match argument.split_at(first) {
+            ("$", offset) => offset
+                .parse::<usize>()
+                .ok()
+                .and_then(|offset| machine.locals().get(offset)),
+            ("#", offset) => offset.parse::<usize>().ok().and_then(|offset| {
+                let closure = machine.get_closure().ok()?;
+                closure.capture.get(offset)
+            }),
+            _ if argument == "stack" => return println!("{:?}", machine.stack),
+            _ => return println!("只能打印 stack, $n 或者 #n"),
+        };
+        match value {
+            Some(value) => println!("{} = {:?}", argument, value),
+            None => println!("没有 {}", argument),
+        }
+    }
+
+    // 暂停时还没有执行下一条指令, 最里层显示下一条指令的位置
+    fn backtrace(&self) {
+        let mut backtrace = match &self.result {
+            Some(Err(err)) => err.backtrace.clone(),
+            _ => self.runtime.backtrace(),
+        };
+        if self.result.is_none() {
+            let index = self.runtime.index();
+            backtrace[0].index = index;
+            backtrace[0].location = self.runtime.asm().source_location(index);
+        }
+        for frame in backtrace {
+            println!("  {}", frame);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+use crate::assemble::{Asm, Operand};
+use crate::command::Oper;
+use crate::error::DecodeError;
+use crate::util::is_identifier;
+
+// 标识符只能由小写字母和下划线组成, 所以用字母给函数编号: a, b, .., z, aa, ab, ..
+fn numbered(mut index: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'a' + (index % 26) as u8);
+        index /= 26;
+        if index == 0 {
+            break;
+        }
+        index -= 1;
+    }
+    name.reverse();
+    format!("func_{}", String::from_utf8(name).unwrap())
+}
+
+fn escape_str(str: &str) -> String {
+    let mut result = String::with_capacity(str.len());
+    for c in str.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '"' => result.push_str("\\\""),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_ascii_control() => result.push_str(&format!("\\x{:02x}", c as u8)),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn escape_byte(byte: u8) -> String {
+    match byte {
+        b'\\' | b'\'' => format!("'\\x{:02x}'", byte),
+        0x20..=0x7e => format!("'{}'", byte as char),
+        _ => format!("'\\x{:02x}'", byte),
+    }
+}
+
+fn format_list(list: &[usize]) -> String {
+    let list = list
+        .iter()
+        .map(usize::to_string)
+        .collect::<Vec<String>>()
+        .join(" ");
+    format!("[{}]", list)
+}
+
+impl Asm {
+    // 给函数池里的每个函数一个合法且不重复的名字, 不合法的名字用编号代替
+    fn label_names(&self, labels: &[String]) -> Vec<String> {
+        let mut used = HashSet::new();
+        let mut names = Vec::with_capacity(self.function_pool.len());
+        let mut counter = 0;
+        for index in 0..self.function_pool.len() {
+            // 反汇编的结果是单个文件, 命名空间里的 math.square 写成 math_square
+            let label = labels.get(index).map(|label| label.replace('.', "_"));
+            let name = match label {
+                Some(label) if is_identifier(&label) && !used.contains(&label) => label,
+                _ => loop {
+                    let name = numbered(counter);
+                    counter += 1;
+                    if !used.contains(&name) && !labels.contains(&name) {
+                        break name;
+                    }
+                },
+            };
+            used.insert(name.clone());
+            names.push(name);
+        }
+        names
+    }
+
+    // 把字节码还原成可以重新汇编的源码, 每行一条指令, 这样不会受到行内倒序的影响
+    pub fn disassemble(&self, labels: &[String]) -> Result<String, DecodeError> {
+        use Oper::*;
+        let names = self.label_names(labels);
+        let mut positions = BTreeMap::<usize, Vec<usize>>::new();
+        for (func, ip) in self.function_pool.iter().enumerate() {
+            if *ip >= self.cmds.len() {
+                return Err(DecodeError::BadIndex(*ip));
+            }
+            positions.entry(*ip).or_default().push(func);
+        }
+
+        let mut source = String::new();
+        let mut index = 0;
+        while index < self.cmds.len() {
+            let labelled = positions.get(&index);
+            for func in labelled.into_iter().flatten() {
+                writeln!(source, "{}:", names[*func]).unwrap();
+            }
+
+            let (oper, operand, mut next) = self.decode(index)?;
+            // 文件末尾的End会由汇编器自动加上
+            if next == self.cmds.len() && matches!(oper, End) && labelled.is_none() {
+                break;
+            }
+
+            let name = |offset: usize| names.get(offset).ok_or(DecodeError::BadIndex(index));
+            let line = match (oper, operand) {
+                (Local, Operand::Offset(offset)) => format!("${}", offset),
+                (Push, Operand::Offset(offset)) => format!("(${})", offset),
+                (Capped, Operand::Offset(offset)) => format!("#{}", offset),
+                // 尾调用后面的Ret还在, 重新汇编时会再次生成尾调用
+                (TailLocal, Operand::Offset(offset)) => format!("${}", offset),
+                (TailCapped, Operand::Offset(offset)) => format!("#{}", offset),
+                (TailCall, Operand::Offset(offset)) => name(offset)?.clone(),
+                (Sys, Operand::Offset(offset)) => format!(
+                    "Sys {}",
+                    self.syscall_pool
+                        .get(offset)
+                        .ok_or(DecodeError::BadIndex(index))?
+                ),
+                (PushCap, Operand::Offset(offset)) => format!("(#{})", offset),
+                (Capture, Operand::List(list)) => format!("${}", format_list(&list)),
+                (CapCap, Operand::List(list)) => format!("#{}", format_list(&list)),
+                (Byte, Operand::Byte(byte)) => escape_byte(byte),
+                (Call, Operand::Offset(offset)) => name(offset)?.clone(),
+                (Func, Operand::Offset(offset)) => format!("({})", name(offset)?),
+                (Num, Operand::Offset(offset)) => {
+                    let number = self
+                        .number_pool
+                        .get(offset)
+                        .ok_or(DecodeError::BadIndex(index))?
+                        .0;
+                    if number.is_nan() {
+                        return Err(DecodeError::Unrepresentable(index));
+                    } else if number.is_infinite() {
+                        format!("{}1e999", if number < 0.0 { "-" } else { "" })
+                    } else {
+                        // 很大或很小的数用科学计数法更短
+                        let (plain, short) = (format!("{}", number), format!("{:?}", number));
+                        if short.len() < plain.len() {
+                            short
+                        } else {
+                            plain
+                        }
+                    }
+                }
+                (Str, Operand::Offset(offset)) => {
+                    let str = self
+                        .string_pool
+                        .get(offset)
+                        .ok_or(DecodeError::BadIndex(index))?;
+                    format!("\"{}\"", escape_str(str))
+                }
+                (Pop, _) => "Let".to_owned(),
+                (NewList, _) => match self.decode(next) {
+                    Ok((Collect, _, after)) if !positions.contains_key(&next) => {
+                        next = after;
+                        "List".to_owned()
+                    }
+                    _ => "NewList".to_owned(),
+                },
+                (oper, _) => format!("{:?}", oper),
+            };
+            writeln!(source, "  {}", line).unwrap();
+            index = next;
+        }
+        Ok(source)
+    }
+}
@@ -1,34 +1,246 @@
+use crate::command::Oper;
+use crate::value::Value;
+use crate::verify::Diagnostic;
+
+#[derive(Debug)]
 pub enum ErrorMessage {
     OverFlow,
     UnderFlow,
     EmptyList,
     NotaList,
     NotaClosure,
+    NoSuchLocal,
+    NoSuchCapture,
     RestEmpty,
     HeadEmpty,
     ConcatNotList,
     PrintErr,
+    ReadErr,
+    TooDeep,
+    TooManyLocals,
+    ListTooLong,
+    DivByZero,
+    OutOfFuel,
+    Timeout,
+    Interrupted,
+    UnknownSyscall,
+    // 系统调用自己报的错
+    Syscall(String),
+    // Throw抛出的值, 没有被Try接住
+    Throw(Value),
+}
+
+impl ErrorMessage {
+    // 不会变的错误种类, Try的处理函数靠它区分内置的错误
+    pub fn kind(&self) -> &'static str {
+        use ErrorMessage::*;
+        match self {
+            OverFlow => "overflow",
+            UnderFlow => "underflow",
+            EmptyList => "empty_list",
+            NotaList => "not_a_list",
+            NotaClosure => "not_a_closure",
+            NoSuchLocal => "no_such_local",
+            NoSuchCapture => "no_such_capture",
+            RestEmpty => "rest_empty",
+            HeadEmpty => "head_empty",
+            ConcatNotList => "concat_not_list",
+            PrintErr => "print",
+            ReadErr => "read",
+            TooDeep => "too_deep",
+            TooManyLocals => "too_many_locals",
+            ListTooLong => "list_too_long",
+            DivByZero => "div_by_zero",
+            OutOfFuel => "out_of_fuel",
+            Timeout => "timeout",
+            Interrupted => "interrupted",
+            UnknownSyscall => "unknown_syscall",
+            Syscall(_) => "syscall",
+            Throw(_) => "throw",
+        }
+    }
+
+    // 不带结尾表情的错误信息
+    pub fn text(&self) -> String {
+        use ErrorMessage::*;
+        match self {
+            OverFlow => "栈溢出了!".to_owned(),
+            UnderFlow => "栈见底了!".to_owned(),
+            EmptyList => "空列表!".to_owned(),
+            NotaList => "类型错误，这不是列表!".to_owned(),
+            NotaClosure => "类型错误，这不是闭包!".to_owned(),
+            NoSuchLocal => "没有这个局部变量!".to_owned(),
+            NoSuchCapture => "闭包没有捕获这个变量!".to_owned(),
+            RestEmpty => "不可以从空列表中取尾部!".to_owned(),
+            HeadEmpty => "不可以从空列表中取头部!".to_owned(),
+            ConcatNotList => "Concat需要两个列表!".to_owned(),
+            PrintErr => "输出时错误".to_owned(),
+            ReadErr => "输入时错误".to_owned(),
+            TooDeep => "调用层数太深了!".to_owned(),
+            TooManyLocals => "局部变量太多了!".to_owned(),
+            ListTooLong => "列表太长了!".to_owned(),
+            DivByZero => "不可以对0取余!".to_owned(),
+            OutOfFuel => "指令数用完了!".to_owned(),
+            Timeout => "运行超时了!".to_owned(),
+            Interrupted => "程序被中断了!".to_owned(),
+            UnknownSyscall => "没有这个系统调用!".to_owned(),
+            Syscall(message) => message.clone(),
+            // 字符串完整地显示出来, 不像栈上那样截断
+            Throw(Value::String(str)) => format!("没有被捕获的异常: {}", str),
+            Throw(value) => format!("没有被捕获的异常: {:?}", value),
+        }
+    }
 }
 
 impl std::fmt::Display for ErrorMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_str())
+        write!(f, "{} :(", self.text())
     }
 }
 
-impl ErrorMessage {
-    fn to_str(&self) -> &'static str {
-        use ErrorMessage::*;
+// 调用栈中的一帧, 最里层的在最前面
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    pub function: String,
+    pub index: usize,
+    pub location: Option<String>,
+}
+
+impl std::fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{} ({})", self.function, location),
+            None => write!(f, "{} (@{})", self.function, self.index),
+        }
+    }
+}
+
+// 运行时错误, 记录出错的指令和当时的调用栈
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: ErrorMessage,
+    pub oper: Oper,
+    pub index: usize,
+    pub location: Option<String>,
+    pub backtrace: Vec<TraceFrame>,
+}
+
+// 递归很深时只显示最里面的几层
+const BACKTRACE_LIMIT: usize = 16;
+
+impl RuntimeError {
+    pub fn format_backtrace(&self) -> String {
+        let mut result = String::new();
+        for frame in self.backtrace.iter().take(BACKTRACE_LIMIT) {
+            result.push_str(&format!("\t  {}\n", frame));
+        }
+        if self.backtrace.len() > BACKTRACE_LIMIT {
+            let rest = self.backtrace.len() - BACKTRACE_LIMIT;
+            result.push_str(&format!("\t  ... 还有{}层\n", rest));
+        }
+        result
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        match &self.location {
+            Some(location) => writeln!(f, "\t{:?} 在 {}", self.oper, location)?,
+            None => writeln!(f, "\t{:?} 在字节码位置 {}", self.oper, self.index)?,
+        }
+        writeln!(f, "\t调用栈:")?;
+        write!(f, "{}", self.format_backtrace())
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    NotBytecode,
+    Version(u16),
+    Truncated,
+    Checksum,
+    TrailingData,
+    BadString,
+    MissingSection(u8),
+    DuplicateSection(u8),
+    BadDebugInfo,
+    // 能读出来, 但是没有通过校验, 运行时会越界
+    Invalid(Vec<Diagnostic>),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use LoadError::*;
+        match self {
+            NotBytecode => write!(f, "这不是字节码文件! :("),
+            Version(version) => write!(f, "不支持的字节码版本: {} :(", version),
+            Truncated => write!(f, "字节码文件不完整! :("),
+            Checksum => write!(f, "字节码校验和不匹配, 文件可能损坏了! :("),
+            TrailingData => write!(f, "字节码段末尾有多余数据! :("),
+            BadString => write!(f, "字符串常量不是合法的UTF-8! :("),
+            MissingSection(kind) => write!(f, "缺少字节码段: {} :(", kind),
+            DuplicateSection(kind) => write!(f, "重复的字节码段: {} :(", kind),
+            BadDebugInfo => write!(f, "调试信息和字节码对不上! :("),
+            Invalid(diagnostics) => {
+                write!(f, "字节码没有通过检查! :(")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n\t{}", diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    UnknownOper(usize, u8),
+    Truncated(usize),
+    Overflow(usize),
+    BadIndex(usize),
+    Unrepresentable(usize),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use DecodeError::*;
+        match self {
+            UnknownOper(index, byte) => write!(f, "位置{}: 不认识的操作符 {} :(", index, byte),
+            Truncated(index) => write!(f, "位置{}: 字节码不完整! :(", index),
+            Overflow(index) => write!(f, "位置{}: 操作数太大了! :(", index),
+            BadIndex(index) => write!(f, "位置{}: 常量池下标越界! :(", index),
+            Unrepresentable(index) => write!(f, "位置{}: 这个常量没法写成源码! :(", index),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    UnknownOper(u8),
+    Truncated,
+    Overflow,
+    BadString(usize),
+    BadNumber(usize),
+    BadFunction(usize),
+    BadSyscall(usize),
+    BadTarget(usize),
+    FallsOffEnd,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use VerifyError::*;
         match self {
-            OverFlow => "栈溢出了! :(",
-            UnderFlow => "栈见底了! :(",
-            EmptyList => "空列表! :(",
-            NotaList => "类型错误，这不是列表! :(",
-            NotaClosure => "类型错误，这不是闭包! :(",
-            RestEmpty => "不可以从空列表中取尾部! :(",
-            HeadEmpty => "不可以从空列表中取头部! :(",
-            ConcatNotList => "Concat需要两个列表! :(",
-            PrintErr => "输出时错误 :(",
+            UnknownOper(byte) => write!(f, "不认识的操作符 {} :(", byte),
+            Truncated => write!(f, "指令的操作数不完整! :("),
+            Overflow => write!(f, "指令的操作数太大了! :("),
+            BadString(index) => write!(f, "字符串常量 {} 不存在! :(", index),
+            BadNumber(index) => write!(f, "数字常量 {} 不存在! :(", index),
+            BadFunction(index) => write!(f, "函数 {} 不存在! :(", index),
+            BadSyscall(index) => write!(f, "系统调用 {} 不存在! :(", index),
+            BadTarget(index) => write!(f, "函数 {} 的入口不是一条指令的开头! :(", index),
+            FallsOffEnd => write!(f, "程序最后一条指令不是End或Ret! :("),
         }
     }
 }
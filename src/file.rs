@@ -0,0 +1,157 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::rc::Rc;
+
+use crate::error::ErrorMessage;
+use crate::machine::Machine;
+use crate::runtime::EOF;
+use crate::syscall::Syscalls;
+use crate::value::Value;
+
+enum Stream {
+    Reader(BufReader<File>),
+    Writer(BufWriter<File>),
+    Closed,
+}
+
+// 程序只能拿着它调用file.*, 看不到里面的文件
+pub struct Handle {
+    pub path: String,
+    stream: RefCell<Stream>,
+}
+
+fn error(message: String) -> ErrorMessage {
+    ErrorMessage::Syscall(message)
+}
+
+fn pop_path(machine: &mut Machine) -> Result<String, ErrorMessage> {
+    match machine.pop()? {
+        Value::String(path) => Ok(path.to_string()),
+        value => Err(error(format!("文件名必须是字符串, 而不是{:?}", value))),
+    }
+}
+
+fn pop_handle(machine: &mut Machine) -> Result<Rc<Handle>, ErrorMessage> {
+    match machine.pop()? {
+        Value::Handle(handle) => Ok(handle),
+        value => Err(error(format!("{:?}不是打开的文件", value))),
+    }
+}
+
+impl Handle {
+    fn open(path: String, options: &OpenOptions, write: bool) -> Result<Value, ErrorMessage> {
+        let file = options
+            .open(&path)
+            .map_err(|err| error(format!("无法打开 {}: {}", path, err)))?;
+        let stream = if write {
+            Stream::Writer(BufWriter::new(file))
+        } else {
+            Stream::Reader(BufReader::new(file))
+        };
+        Ok(Value::Handle(Rc::new(Handle {
+            path,
+            stream: RefCell::new(stream),
+        })))
+    }
+
+    fn reader<T>(
+        &self,
+        read: impl FnOnce(&mut BufReader<File>) -> std::io::Result<T>,
+    ) -> Result<T, ErrorMessage> {
+        match &mut *self.stream.borrow_mut() {
+            Stream::Reader(reader) => {
+                read(reader).map_err(|err| error(format!("读取 {} 失败: {}", self.path, err)))
+            }
+            Stream::Writer(_) => Err(error(format!("{} 不是以读取方式打开的", self.path))),
+            Stream::Closed => Err(error(format!("{} 已经关闭了", self.path))),
+        }
+    }
+
+    fn writer(&self, bytes: &[u8]) -> Result<(), ErrorMessage> {
+        match &mut *self.stream.borrow_mut() {
+            Stream::Writer(writer) => writer
+                .write_all(bytes)
+                .map_err(|err| error(format!("写入 {} 失败: {}", self.path, err))),
+            Stream::Reader(_) => Err(error(format!("{} 不是以写入方式打开的", self.path))),
+            Stream::Closed => Err(error(format!("{} 已经关闭了", self.path))),
+        }
+    }
+
+    // 写入的内容在关闭时才保证落盘, 关闭两次会报错
+    fn close(&self) -> Result<(), ErrorMessage> {
+        match self.stream.replace(Stream::Closed) {
+            Stream::Writer(mut writer) => writer
+                .flush()
+                .map_err(|err| error(format!("写入 {} 失败: {}", self.path, err))),
+            Stream::Reader(_) => Ok(()),
+            Stream::Closed => Err(error(format!("{} 已经关闭了", self.path))),
+        }
+    }
+}
+
+// 参数从左往右写, 比如 Sys file.write $0 "text", 读到结尾时和Input一样放-1
+pub(crate) fn register(syscalls: &mut Syscalls) {
+    syscalls.register("file.open", |machine| {
+        let path = pop_path(machine)?;
+        machine.push(Handle::open(path, OpenOptions::new().read(true), false)?)
+    });
+
+    syscalls.register("file.create", |machine| {
+        let path = pop_path(machine)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        machine.push(Handle::open(path, &options, true)?)
+    });
+
+    syscalls.register("file.append", |machine| {
+        let path = pop_path(machine)?;
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        machine.push(Handle::open(path, &options, true)?)
+    });
+
+    syscalls.register("file.read", |machine| {
+        let handle = pop_handle(machine)?;
+        let mut content = String::new();
+        handle.reader(|reader| reader.read_to_string(&mut content))?;
+        machine.push(Value::String(content.into()))
+    });
+
+    // 去掉行尾的换行符
+    syscalls.register("file.read_line", |machine| {
+        let handle = pop_handle(machine)?;
+        let mut line = String::new();
+        if handle.reader(|reader| reader.read_line(&mut line))? == 0 {
+            return machine.push(Value::Number(EOF));
+        }
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        machine.push(Value::String(line.into()))
+    });
+
+    syscalls.register("file.read_byte", |machine| {
+        let handle = pop_handle(machine)?;
+        let mut buffer = [0u8; 1];
+        let count = handle.reader(|reader| reader.read(&mut buffer))?;
+        match count {
+            0 => machine.push(Value::Number(EOF)),
+            _ => machine.push(Value::Byte(buffer[0])),
+        }
+    });
+
+    // 数字和布尔值和Print一样写成文字, 字节原样写入
+    syscalls.register("file.write", |machine| {
+        let handle = pop_handle(machine)?;
+        let bytes = match machine.pop()? {
+            Value::String(str) => str.as_bytes().to_vec(),
+            Value::Byte(byte) => vec![byte],
+            Value::Number(number) => number.to_string().into_bytes(),
+            Value::Bool(boolean) => boolean.to_string().into_bytes(),
+            value => return Err(error(format!("不能把{:?}写入文件", value))),
+        };
+        handle.writer(&bytes)
+    });
+
+    syscalls.register("file.close", |machine| pop_handle(machine)?.close());
+}
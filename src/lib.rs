@@ -0,0 +1,46 @@
+//! Sognac的字节码汇编器和解释器
+//!
+//! ```no_run
+//! use sognasm::{Asm, AsmBuilder, Runtime};
+//!
+//! let builder = AsmBuilder::from_str("Print \"hello\"").unwrap();
+//! let asm = Asm::from(builder);
+//! let status = Runtime::run(&asm).unwrap();
+//! println!("{:?}", status.stack);
+//! ```
+pub mod assemble;
+mod clock;
+pub mod command;
+pub mod coverage;
+pub mod debug;
+mod disasm;
+pub mod error;
+pub mod file;
+mod loader;
+pub mod machine;
+mod optimize;
+pub mod parser;
+pub mod profile;
+pub mod program;
+mod random;
+mod record;
+pub mod runtime;
+pub mod syscall;
+#[cfg(test)]
+mod test;
+mod util;
+pub mod value;
+pub mod verify;
+pub mod writer;
+
+pub use assemble::Asm;
+pub use coverage::Coverage;
+pub use error::{DecodeError, ErrorMessage, LoadError, RuntimeError, VerifyError};
+pub use machine::{Limits, Machine};
+pub use parser::AsmBuilder;
+pub use profile::Profile;
+pub use program::Program;
+pub use runtime::{ExitStatus, Runtime};
+pub use syscall::Syscalls;
+pub use value::Value;
+pub use writer::is_bytecode;
@@ -0,0 +1,209 @@
+use crate::assemble::Asm;
+use crate::command::Cmd;
+use crate::debug::{DebugInfo, Location};
+use crate::error::LoadError;
+use crate::error::LoadError::*;
+use crate::parser::Number;
+use crate::writer::{checksum, Section, ENTRY_SIZE, HEADER_SIZE, MAGIC, MIN_VERSION, VERSION};
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, index: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.index.checked_add(length).ok_or(Truncated)?;
+        let slice = self.bytes.get(self.index..end).ok_or(Truncated)?;
+        self.index = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, LoadError> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, LoadError> {
+        let count = self.u32()?;
+        let mut strings = Vec::new();
+        for _ in 0..count {
+            let length = self.u32()? as usize;
+            let bytes = self.take(length)?;
+            let str = std::str::from_utf8(bytes).map_err(|_| BadString)?;
+            strings.push(str.to_owned());
+        }
+        Ok(strings)
+    }
+
+    fn finish(&self) -> Result<(), LoadError> {
+        if self.index == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(TrailingData)
+        }
+    }
+}
+
+impl Asm {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(NotBytecode);
+        }
+        if bytes.len() < HEADER_SIZE + 4 {
+            return Err(Truncated);
+        }
+        let (body, sum) = bytes.split_at(bytes.len() - 4);
+        if checksum(body) != u32::from_le_bytes(sum.try_into().unwrap()) {
+            return Err(Checksum);
+        }
+
+        let mut reader = Reader::new(body);
+        reader.take(MAGIC.len())?;
+        let version = reader.u16()?;
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(Version(version));
+        }
+
+        let count = reader.u16()? as usize;
+        if HEADER_SIZE + ENTRY_SIZE * count > body.len() {
+            return Err(Truncated);
+        }
+        let mut sections: [Option<&[u8]>; 6] = [None; 6];
+        for _ in 0..count {
+            let kind = reader.u8()?;
+            let offset = reader.u32()? as usize;
+            let length = reader.u32()? as usize;
+            let data = Reader {
+                bytes: body,
+                index: offset,
+            }
+            .take(length)?;
+            // 不认识的段直接跳过, 方便以后扩展
+            if let Some(section) = Section::from_u8(kind) {
+                let slot = &mut sections[section as usize - 1];
+                if slot.is_some() {
+                    return Err(DuplicateSection(kind));
+                }
+                *slot = Some(data);
+            }
+        }
+
+        let section = |kind: Section| sections[kind as usize - 1].ok_or(MissingSection(kind as u8));
+        let cmds = section(Section::Code)?
+            .iter()
+            .map(|byte| Cmd(*byte))
+            .collect();
+        let string_pool = read_strings(section(Section::Strings)?)?;
+        let number_pool = read_numbers(section(Section::Numbers)?)?;
+        let function_pool = read_functions(section(Section::Functions)?)?;
+
+        let mut asm = Asm::new(cmds, string_pool, number_pool, function_pool);
+        if let Some(data) = sections[Section::Syscalls as usize - 1] {
+            asm.syscall_pool = read_strings(data)?;
+        }
+        // 调试信息是可选的
+        if let Some(data) = sections[Section::Debug as usize - 1] {
+            asm.debug = Some(read_debug(data, asm.cmds.len(), asm.function_pool.len())?);
+        }
+        // 字节码可能来自别处, 校验过才能交给Runtime
+        asm.verify().map_err(Invalid)?;
+        Ok(asm)
+    }
+}
+
+fn read_strings(data: &[u8]) -> Result<Vec<String>, LoadError> {
+    let mut reader = Reader::new(data);
+    let pool = reader.strings()?;
+    reader.finish()?;
+    Ok(pool)
+}
+
+fn read_numbers(data: &[u8]) -> Result<Vec<Number>, LoadError> {
+    let mut reader = Reader::new(data);
+    let count = reader.u32()?;
+    let mut pool = Vec::new();
+    for _ in 0..count {
+        pool.push(Number(reader.f64()?));
+    }
+    reader.finish()?;
+    Ok(pool)
+}
+
+fn read_functions(data: &[u8]) -> Result<Vec<usize>, LoadError> {
+    let mut reader = Reader::new(data);
+    let count = reader.u32()?;
+    let mut pool = Vec::new();
+    for _ in 0..count {
+        pool.push(reader.u32()? as usize);
+    }
+    reader.finish()?;
+    Ok(pool)
+}
+
+fn read_debug(data: &[u8], length: usize, functions: usize) -> Result<DebugInfo, LoadError> {
+    let mut reader = Reader::new(data);
+    let files = reader.strings()?;
+
+    // 每一段的起始位置必须从0开始严格递增
+    let count = reader.u32()?;
+    let mut runs = Vec::<(usize, Location)>::new();
+    for _ in 0..count {
+        let start = reader.u32()? as usize;
+        let location = Location {
+            file: reader.u32()? as usize,
+            line: reader.u32()? as usize,
+            column: reader.u32()? as usize,
+        };
+        let ordered = match runs.last() {
+            Some((last, _)) => start > *last,
+            None => start == 0,
+        };
+        if !ordered || start >= length || location.file >= files.len() {
+            return Err(BadDebugInfo);
+        }
+        runs.push((start, location));
+    }
+    // 旧的字节码没有标签名
+    let labels = if reader.index == data.len() {
+        Vec::new()
+    } else {
+        reader.strings()?
+    };
+    reader.finish()?;
+    if !labels.is_empty() && labels.len() != functions {
+        return Err(BadDebugInfo);
+    }
+    if runs.is_empty() && length != 0 {
+        return Err(BadDebugInfo);
+    }
+
+    let mut lines = Vec::with_capacity(length);
+    for (index, (_, location)) in runs.iter().enumerate() {
+        let end = runs.get(index + 1).map_or(length, |x| x.0);
+        lines.resize(end, *location);
+    }
+    Ok(DebugInfo {
+        files,
+        lines,
+        labels,
+    })
+}
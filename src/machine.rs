@@ -1,23 +1,126 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use crate::error::ErrorMessage;
 use crate::error::ErrorMessage::*;
 use crate::value::Closure;
 use crate::value::Value;
 
-#[derive(Debug, Default)]
-pub struct Machine<'a> {
-    pub variable: Vec<Value<'a>>,
-    pub stack: Vec<Value<'a>>,
+// 调用栈的一帧
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    // 返回地址
+    pub ret: usize,
+    // 调用指令的位置
+    pub call: usize,
+    // 调用者的局部变量起点
+    pub sp: usize,
+    // 被调用函数的入口
+    pub func: usize,
+}
+
+// Try留下的记录, 出错时回到Try执行时的状态, 再调用处理函数
+#[derive(Debug, Clone)]
+pub struct Handler {
+    // 处理函数
+    pub func: Value,
+    // Try指令的位置
+    pub call: usize,
+    // 处理函数返回到Try之后
+    pub ret: usize,
+    // Try执行时的调用栈, 栈和局部变量的大小
+    pub frames: usize,
+    pub stack: usize,
+    pub variable: usize,
     sp: usize,
+    swapped: bool,
+}
 
-    temp_stack: Vec<Value<'a>>,
+// 运行时的资源限制, 超过限制时各自报不同的错
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // 栈上最多能放的值
+    pub max_stack: usize,
+    // 最深的调用层数
+    pub max_depth: usize,
+    // 所有栈帧的局部变量加起来最多能有多少
+    pub max_locals: usize,
+    // 列表最长的长度
+    pub max_list_len: usize,
 }
 
-impl<'a> Machine<'a> {
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_stack: 256,
+            max_depth: 1 << 16,
+            max_locals: 1 << 20,
+            max_list_len: 1 << 24,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Machine {
+    pub variable: Vec<Value>,
+    pub stack: Vec<Value>,
+    pub frames: Vec<Frame>,
+    // 还没有结束的Try, 最里层的在最后
+    pub handlers: Vec<Handler>,
+    pub limits: Limits,
+    // 由Runtime设置, 放在这里是为了让系统调用也能遵守
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) interrupt: Option<Arc<AtomicBool>>,
+    sp: usize,
+
+    temp_stack: Vec<Value>,
+    // 正在用NewList的新栈
+    swapped: bool,
+}
+
+// 长时间的睡眠分成小段, 每段之间检查超时和中断
+const SLEEP_SLICE: Duration = Duration::from_millis(10);
+
+impl Machine {
     pub fn new() -> Self {
         Machine::default()
     }
 
-    pub fn pop(&mut self) -> Result<Value<'a>, ErrorMessage> {
+    pub fn check_deadline(&self) -> Result<(), ErrorMessage> {
+        if self.deadline.is_some_and(|x| Instant::now() >= x) {
+            Err(Timeout)
+        } else {
+            Ok(())
+        }
+    }
+
+    // 标志不在这里清除, 下一条指令之前Runtime会处理中断
+    pub fn interrupted(&self) -> bool {
+        self.interrupt
+            .as_ref()
+            .is_some_and(|x| x.load(Ordering::Relaxed))
+    }
+
+    // 超过期限时报超时, 被中断时提前醒来
+    pub fn sleep(&self, duration: Duration) -> Result<(), ErrorMessage> {
+        // 太长的时间加不上去, 当成一直睡下去
+        let wake = Instant::now().checked_add(duration);
+        loop {
+            self.check_deadline()?;
+            let now = Instant::now();
+            if self.interrupted() || wake.is_some_and(|x| now >= x) {
+                return Ok(());
+            }
+            let mut slice = SLEEP_SLICE;
+            for end in [wake, self.deadline].into_iter().flatten() {
+                slice = slice.min(end.saturating_duration_since(now));
+            }
+            std::thread::sleep(slice);
+        }
+    }
+
+    pub fn pop(&mut self) -> Result<Value, ErrorMessage> {
         if let Some(value) = self.stack.pop() {
             Ok(value)
         } else {
@@ -25,60 +128,152 @@ impl<'a> Machine<'a> {
         }
     }
 
-    pub fn push(&mut self, v: Value<'a>) -> Result<(), ErrorMessage> {
-        if self.stack.len() > 255 {
+    pub fn push(&mut self, v: Value) -> Result<(), ErrorMessage> {
+        if self.stack.len() >= self.limits.max_stack {
             return Err(OverFlow);
         }
         self.stack.push(v);
         Ok(())
     }
 
-    pub fn local(&self, offset: u8) -> &Value<'a> {
-        &self.variable[self.sp + (offset as usize)]
+    // 字节码里的$n可能超出当前栈帧, 这时报错而不是崩溃
+    pub fn local(&self, offset: usize) -> Result<&Value, ErrorMessage> {
+        self.locals().get(offset).ok_or(NoSuchLocal)
     }
 
-    pub fn push_to_local(&mut self, value: Value<'a>) {
+    // 当前栈帧的局部变量
+    pub fn locals(&self) -> &[Value] {
+        &self.variable[self.sp..]
+    }
+
+    pub fn push_to_local(&mut self, value: Value) -> Result<(), ErrorMessage> {
+        if self.variable.len() >= self.limits.max_locals {
+            return Err(TooManyLocals);
+        }
         self.variable.push(value);
+        Ok(())
     }
 
-    pub fn push_sp(&mut self) {
-        self.variable.push(Value::Function(self.sp));
+    // 进入新的栈帧, 之后的局部变量从这里开始编号
+    pub fn enter(&mut self, ret: usize, call: usize, func: usize) -> Result<(), ErrorMessage> {
+        if self.frames.len() >= self.limits.max_depth {
+            return Err(TooDeep);
+        }
+        self.frames.push(Frame {
+            ret,
+            call,
+            sp: self.sp,
+            func,
+        });
         self.sp = self.variable.len();
+        Ok(())
     }
 
-    pub fn ret_ip(&mut self) -> usize {
-        use Value::Function;
+    // 尾调用: 丢掉当前栈帧的局部变量, 栈帧留给被调用的函数
+    pub fn reenter(&mut self, func: usize) {
         self.variable.truncate(self.sp);
-        let sp = self.variable.pop().unwrap_or(Value::Bool(false));
-        let ip = self.variable.pop().unwrap_or(Value::Bool(false));
-        if let (Function(sp), Function(ip)) = (sp, ip) {
-            self.sp = sp;
-            ip
-        } else {
-            std::process::exit(0)
+        if let Some(frame) = self.frames.last_mut() {
+            frame.func = func;
+        }
+    }
+
+    // 没有可以返回的栈帧时返回None, 说明程序结束了
+    pub fn ret_ip(&mut self) -> Option<usize> {
+        let frame = self.frames.pop()?;
+        self.variable.truncate(self.sp);
+        self.sp = frame.sp;
+        // Try调用的函数正常返回了, 它的处理函数不再需要
+        while self
+            .handlers
+            .last()
+            .is_some_and(|handler| handler.frames >= self.frames.len())
+        {
+            self.handlers.pop();
+        }
+        Some(frame.ret)
+    }
+
+    // 回到最外层, 丢掉还没返回的栈帧和它们的局部变量
+    pub fn return_to_top(&mut self) {
+        if !self.frames.is_empty() {
+            let top = self.frames.get(1).map_or(self.sp, |frame| frame.sp);
+            self.variable.truncate(top);
+            self.frames.clear();
+            self.sp = 0;
+        }
+        self.handlers.clear();
+        if self.swapped {
+            self.swap_temp();
+            self.temp_stack.clear();
         }
     }
 
-    pub fn get_closure(&self) -> Result<&Closure<'a>, ErrorMessage> {
-        if let Value::Closure(closure) = self.local(0) {
+    // 在Try调用函数之前记下现在的状态
+    pub fn guard(&mut self, func: Value, call: usize, ret: usize) {
+        self.handlers.push(Handler {
+            func,
+            call,
+            ret,
+            frames: self.frames.len(),
+            stack: self.stack.len(),
+            variable: self.variable.len(),
+            sp: self.sp,
+            swapped: self.swapped,
+        });
+    }
+
+    // 回到最里层的Try, 丢掉之后的栈帧, 局部变量和栈上的值
+    pub fn unwind(&mut self) -> Option<Handler> {
+        let handler = self.handlers.pop()?;
+        // 出错时列表还没收集完, 先回到原来的栈
+        if self.swapped != handler.swapped {
+            self.swap_temp();
+            self.temp_stack.clear();
+        }
+        self.frames.truncate(handler.frames);
+        self.variable.truncate(handler.variable);
+        self.sp = handler.sp;
+        self.stack.truncate(handler.stack);
+        Some(handler)
+    }
+
+    pub fn get_closure(&self) -> Result<&Closure, ErrorMessage> {
+        if let Ok(Value::Closure(closure)) = self.local(0) {
             Ok(closure)
         } else {
             Err(NotaClosure)
         }
     }
 
+    // 当前闭包捕获的第index个值
+    pub fn captured(&self, index: usize) -> Result<Value, ErrorMessage> {
+        let closure = self.get_closure()?;
+        closure.capture.get(index).cloned().ok_or(NoSuchCapture)
+    }
+
     pub fn swap_temp(&mut self) {
         std::mem::swap(&mut self.stack, &mut self.temp_stack);
+        self.swapped = !self.swapped;
     }
 
-    pub fn collect_list(&mut self) {
+    pub fn collect_list(&mut self) -> Result<(), ErrorMessage> {
         let temp = std::mem::take(&mut self.stack);
+        self.check_list_len(temp.len())?;
         let list = Value::List(temp.into_iter().rev().collect());
         self.swap_temp();
         self.stack.push(list);
+        Ok(())
+    }
+
+    pub fn check_list_len(&self, len: usize) -> Result<(), ErrorMessage> {
+        if len > self.limits.max_list_len {
+            Err(ListTooLong)
+        } else {
+            Ok(())
+        }
     }
 }
-impl std::fmt::Display for &Machine<'_> {
+impl std::fmt::Display for &Machine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "stack: {:?}", self.stack)?;
         write!(f, "local: {:?}", self.variable)
@@ -1,55 +1,318 @@
 mod argus;
-mod assemble;
-mod command;
-mod error;
-mod machine;
-mod parser;
-mod record;
-mod runtime;
-mod test;
-mod util;
-mod value;
+mod debugger;
+mod repl;
 use argus::Arguments;
-use assemble::Asm;
 use colored::Colorize;
-use parser::AsmBuilder;
-use runtime::Runtime;
+use debugger::Debugger;
+use repl::Repl;
+use sognasm::{
+    Asm, AsmBuilder, ErrorMessage, ExitStatus, Program, Runtime, RuntimeError, Syscalls,
+};
 use std::fs;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+fn fail(err: impl std::fmt::Display) -> ! {
+    eprintln!("{} {}", "[error]".red(), err);
+    std::process::exit(1);
+}
+
+fn verify(asm: &Asm) {
+    if let Err(diagnostics) = asm.verify() {
+        for diagnostic in diagnostics {
+            eprintln!("{} {}", "[error]".red(), diagnostic);
+        }
+        std::process::exit(1);
+    }
+}
+
+// --sandbox时只有不碰文件和环境变量的系统调用
+fn host_syscalls(arguments: &Arguments) -> Syscalls {
+    if arguments.is_sandbox() {
+        Syscalls::sandboxed()
+    } else {
+        Syscalls::standard()
+    }
+}
+
+fn syscalls(arguments: &Arguments) -> Syscalls {
+    let syscalls = host_syscalls(arguments).with_args(arguments.args());
+    match arguments.seed() {
+        Some(seed) => syscalls.with_seed(seed),
+        None => syscalls,
+    }
+}
+
+fn runtime<'a>(asm: &'a Asm, arguments: &Arguments) -> Runtime<'a> {
+    let mut runtime = Runtime::new(asm)
+        .with_limits(arguments.limits())
+        .with_syscalls(syscalls(arguments));
+    if let Some(fuel) = arguments.fuel() {
+        runtime = runtime.with_fuel(fuel);
+    }
+    if let Some(timeout) = arguments.timeout() {
+        runtime = runtime.with_timeout(timeout);
+    }
+    runtime
+}
+
+// Ctrl-C只设置标志, 处理函数只能设置一次
+// 程序卡在读取或者睡眠里时标志一直没人处理, 再按一次Ctrl-C直接退出
+fn interrupt_flag() -> Arc<AtomicBool> {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let flag = interrupt.clone();
+    let handler = move || {
+        if flag.swap(true, Ordering::Relaxed) {
+            std::process::exit(INTERRUPTED);
+        }
+    };
+    if let Err(err) = ctrlc::set_handler(handler) {
+        eprintln!("{} 无法捕获Ctrl-C: {}", "[warning]".yellow(), err);
+    }
+    interrupt
+}
+
+// Ctrl-C时停在下一条指令之前, 进入调试器
+fn interruptible(runtime: Runtime) -> Runtime {
+    runtime.with_interrupt(interrupt_flag())
+}
+
+// 格式由RuntimeError的Display决定
+fn report(err: &RuntimeError) {
+    eprint!("\n{} {}", "[error]".red(), err);
+}
+
+// 和被SIGINT杀掉的进程一样
+const INTERRUPTED: i32 = 130;
+
+// 运行时出错时退出码为1, 指令数用完为3, 超时为124
+fn exit_code(err: &RuntimeError) -> i32 {
+    match err.message {
+        ErrorMessage::OutOfFuel => 3,
+        ErrorMessage::Timeout => 124,
+        ErrorMessage::Interrupted => INTERRUPTED,
+        _ => 1,
+    }
+}
+
+// 程序运行结束后按结果决定退出码, 汇编和加载时的错误由fail等函数直接退出
+fn finish(result: Result<ExitStatus, RuntimeError>) {
+    if let Err(err) = result {
+        report(&err);
+        std::process::exit(exit_code(&err));
+    }
+}
+
+fn run(asm: &Asm, arguments: &Arguments, labels: Vec<String>) {
+    let mut runtime = interruptible(runtime(asm, arguments));
+    match runtime.execute(|_, _| {}) {
+        // 标准输入不是终端时它是给程序的输入, 不能拿来读调试命令
+        Err(err)
+            if matches!(err.message, ErrorMessage::Interrupted)
+                && std::io::stdin().is_terminal() =>
+        {
+            println!("\n{}", err.message.to_string().yellow());
+            debug(runtime, labels)
+        }
+        result => finish(result),
+    }
+}
+
+// 程序结束或者出错之后打印统计
+fn profile(asm: &Asm, arguments: &Arguments) {
+    let (result, profile) = interruptible(runtime(asm, arguments)).run_profiling();
+    if let Err(err) = &result {
+        report(err);
+    }
+    eprint!("\n{}", profile.summary());
+    if let Some(folded) = arguments.folded() {
+        if let Err(err) = fs::write(folded, profile.folded()) {
+            fail(err);
+        }
+    }
+    if let Err(err) = result {
+        std::process::exit(exit_code(&err));
+    }
+}
+
+// 程序结束或者出错之后写出覆盖率
+fn coverage(asm: &Asm, arguments: &Arguments, path: &str) {
+    let (result, coverage) = interruptible(runtime(asm, arguments)).run_coverage();
+    if let Err(err) = &result {
+        report(err);
+    }
+    eprint!("\n{}", coverage.summary(asm));
+    let Some(lcov) = coverage.lcov(asm) else {
+        fail("字节码里没有调试信息, 无法对应到源码");
+    };
+    if let Err(err) = fs::write(path, lcov) {
+        fail(err);
+    }
+    if let Err(err) = result {
+        std::process::exit(exit_code(&err));
+    }
+}
+
+fn debug(runtime: Runtime, labels: Vec<String>) {
+    if let Some(Err(err)) = Debugger::new(runtime, labels).run() {
+        std::process::exit(exit_code(&err));
+    }
+}
 
 fn main() {
     let arguments = Arguments::new();
+    if arguments.is_repl() {
+        // Ctrl-C只打断正在执行的那一行
+        Repl::new(&arguments, interrupt_flag()).run();
+        return;
+    }
+
     let file = arguments.source();
-    let content = match fs::read_to_string(file) {
+    let content = match fs::read(file) {
         Ok(file) => file,
+        Err(err) => fail(err),
+    };
+
+    if sognasm::is_bytecode(&content) {
+        let asm = Asm::from_bytes(&content).unwrap_or_else(|err| fail(err));
+        run_bytecode(asm, &arguments);
+        return;
+    }
+
+    let content = String::from_utf8(content).unwrap_or_else(|err| fail(err));
+    let program = match Program::load(file, content, &arguments.include()) {
+        Ok(program) => program,
         Err(err) => {
-            eprintln!("{} {}", "[error]".red(), err);
+            eprintln!("{}", err);
             std::process::exit(1);
         }
     };
-
-    let builder = match AsmBuilder::from_str(&content) {
+    let mut builder = match AsmBuilder::from_program(&program) {
         Ok(builder) => builder,
         Err(err) => {
             eprintln!("{}", err);
             std::process::exit(1);
         }
     };
+    if let Err(err) = builder.check_syscalls(&host_syscalls(&arguments)) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+    if arguments.is_optimize() {
+        builder.optimize();
+    }
+
+    if arguments.is_debug() {
+        let (asm, labels) = Asm::from_builder(builder);
+        verify(&asm);
+        debug(interruptible(runtime(&asm, &arguments)), labels);
+        return;
+    }
+
+    if arguments.is_disassemble() {
+        let (asm, labels) = Asm::with_labels(builder);
+        disassemble(&asm, &labels, &arguments);
+        return;
+    }
+
+    if let Some(output) = arguments.output() {
+        let asm = Asm::from(builder);
+        if let Err(err) = fs::write(output, asm.to_bytes()) {
+            fail(err);
+        }
+        return;
+    }
 
     match (arguments.is_print(), arguments.is_code()) {
         (true, true) => {
             let (asm, labels) = Asm::from_builder(builder);
-            Runtime::run_printing_code(asm, arguments.speed(), labels);
+            verify(&asm);
+            finish(runtime(&asm, &arguments).run_printing_code(arguments.speed(), labels));
         }
         (true, false) => {
             let asm = Asm::from(builder);
-            Runtime::run_printing(asm, arguments.speed());
+            verify(&asm);
+            finish(runtime(&asm, &arguments).run_printing(arguments.speed()));
         }
         (false, true) => {
             builder.display(0);
         }
+        (false, false) if arguments.coverage().is_some() => {
+            let (asm, _) = Asm::with_dead_functions(builder);
+            verify(&asm);
+            coverage(&asm, &arguments, arguments.coverage().unwrap());
+        }
+        (false, false) if arguments.is_profile() => {
+            let (asm, _) = Asm::from_builder(builder);
+            verify(&asm);
+            profile(&asm, &arguments);
+        }
         (false, false) => {
-            let asm = Asm::from(builder);
-            Runtime::run(asm);
+            // 所有标签都放进函数池, 这样Ctrl-C进入调试器之后可以在任意标签上设置断点
+            let (asm, labels) = Asm::from_builder(builder);
+            verify(&asm);
+            run(&asm, &arguments, labels);
+        }
+    }
+}
+
+fn disassemble(asm: &Asm, labels: &[String], arguments: &Arguments) {
+    let source = asm.disassemble(labels).unwrap_or_else(|err| fail(err));
+    match arguments.output() {
+        Some(output) => {
+            if let Err(err) = fs::write(output, source) {
+                fail(err);
+            }
+        }
+        None => print!("{}", source),
+    }
+}
+
+fn run_bytecode(asm: Asm, arguments: &Arguments) {
+    // 没有调试信息的字节码里没有标签名, 用函数地址代替
+    let labels: Vec<String> = asm
+        .function_pool
+        .iter()
+        .map(|ip| asm.function_name(*ip))
+        .collect();
+
+    if arguments.is_disassemble() {
+        disassemble(&asm, &labels, arguments);
+        return;
+    }
+
+    if let Some(output) = arguments.output() {
+        if let Err(err) = fs::write(output, asm.to_bytes()) {
+            fail(err);
+        }
+        return;
+    }
+
+    // 字节码可能是用注册了别的系统调用的程序生成的
+    let syscalls = host_syscalls(arguments);
+    if let Some(name) = asm.syscall_pool.iter().find(|x| !syscalls.contains(x)) {
+        fail(format!("未知的系统调用 {}", name));
+    }
+
+    if arguments.is_debug() {
+        debug(interruptible(runtime(&asm, arguments)), labels);
+        return;
+    }
+
+    match (arguments.is_print(), arguments.is_code()) {
+        (true, true) => {
+            finish(runtime(&asm, arguments).run_printing_code(arguments.speed(), labels))
+        }
+        (true, false) => finish(runtime(&asm, arguments).run_printing(arguments.speed())),
+        (false, true) => {
+            asm.display(0, &labels);
+            println!("\x1b[0m");
+        }
+        (false, false) if arguments.coverage().is_some() => {
+            coverage(&asm, arguments, arguments.coverage().unwrap())
         }
+        (false, false) if arguments.is_profile() => profile(&asm, arguments),
+        (false, false) => run(&asm, arguments, labels),
     }
 }
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use crate::command::Oper;
+use crate::parser::{AsmBuilder, AsmCmd, Number, Pos};
+use crate::value::Value;
+
+// 把AsmBuilder中的命令按指令分组, 操作数跟着它的操作符
+#[derive(Clone)]
+enum Inst<'a> {
+    Label(AsmCmd<'a>, Pos<'a>),
+    Op {
+        oper: Oper,
+        operand: Option<AsmCmd<'a>>,
+        pos: Pos<'a>,
+    },
+}
+
+impl<'a> Inst<'a> {
+    fn oper(&self) -> Option<Oper> {
+        match self {
+            Inst::Op { oper, .. } => Some(*oper),
+            Inst::Label(..) => None,
+        }
+    }
+
+    fn pos(&self) -> Pos<'a> {
+        match self {
+            Inst::Op { pos, .. } | Inst::Label(_, pos) => *pos,
+        }
+    }
+
+    // 编译期就能知道值的常量
+    fn constant(&self) -> Option<Value> {
+        match self {
+            Inst::Op {
+                oper: Oper::Num,
+                operand: Some(AsmCmd::Number(number)),
+                ..
+            } => Some(Value::Number(number.0)),
+            Inst::Op {
+                oper: Oper::Byte,
+                operand: Some(AsmCmd::Byte(byte)),
+                ..
+            } => Some(Value::Byte(*byte)),
+            Inst::Op {
+                oper: Oper::True, ..
+            } => Some(Value::Bool(true)),
+            Inst::Op {
+                oper: Oper::False, ..
+            } => Some(Value::Bool(false)),
+            _ => None,
+        }
+    }
+
+    // 只往栈上放一个值, 没有别的作用的指令
+    fn is_pure(&self) -> bool {
+        use Oper::*;
+        matches!(self.oper(), Some(Num | Str | Byte | Func | True | False))
+    }
+
+    // 执行结果一定是Bool的指令
+    fn is_bool(&self) -> bool {
+        use Oper::*;
+        matches!(
+            self.oper(),
+            Some(Lt | Gt | Eq | Le | Ge | And | Or | Not | Empty | True | False)
+        )
+    }
+
+    fn from_value(value: Value, pos: Pos<'a>) -> Option<Self> {
+        let (oper, operand) = match value {
+            // NaN没法写回源码, 留到运行时再算
+            Value::Number(number) if number.is_nan() => return None,
+            Value::Number(number) => (Oper::Num, Some(AsmCmd::Number(Number(number)))),
+            Value::Bool(true) => (Oper::True, None),
+            Value::Bool(false) => (Oper::False, None),
+            _ => return None,
+        };
+        Some(Inst::Op { oper, operand, pos })
+    }
+}
+
+// 和Runtime::binary_*的语义保持一致, a是栈顶的值
+fn fold_binary(oper: Oper, a: Value, b: Value) -> Option<Value> {
+    use Oper::*;
+    use Value::*;
+    let (x, y) = (a.clone().into_number(), b.clone().into_number());
+    let (i, j) = (a.clone().into_integer(), b.clone().into_integer());
+    let (p, q) = (a.into_bool(), b.into_bool());
+    let value = match oper {
+        Add => Number(x + y),
+        Sub => Number(x - y),
+        SubBy => Number(y - x),
+        Div => Number(x / y),
+        DivBy => Number(y / x),
+        Mul => Number(x * y),
+        // 对0取余在运行时会报错, 不能提前折叠
+        Mod => Number(i.checked_rem(j)? as f64),
+        ModBy => Number(j.checked_rem(i)? as f64),
+        Xor => Number((i ^ j) as f64),
+        BitOr => Number((i | j) as f64),
+        BitAnd => Number((i & j) as f64),
+        And => Bool(p && q),
+        Or => Bool(p || q),
+        Lt => Bool(x < y),
+        Gt => Bool(x > y),
+        Eq => Bool(x == y),
+        Le => Bool(x <= y),
+        Ge => Bool(x >= y),
+        _ => return None,
+    };
+    Some(value)
+}
+
+// 在insts[index]处尝试一次化简, 返回替换的长度和新的指令
+fn rewrite<'a>(insts: &[Inst<'a>], index: usize) -> Option<(usize, Vec<Inst<'a>>)> {
+    use Oper::*;
+    let window = &insts[index..];
+    let pos = |len: usize| window[len - 1].pos();
+    let oper = |i: usize| window.get(i).and_then(Inst::oper);
+
+    // b a If
+    if window.len() >= 4
+        && matches!(oper(3), Some(If))
+        && window[0].is_pure()
+        && window[1].is_pure()
+    {
+        if let Some(cond) = window[2].constant() {
+            let keep = if cond.into_bool() { 1 } else { 0 };
+            return Some((4, vec![window[keep].clone()]));
+        }
+    }
+
+    // b a Op
+    if window.len() >= 3 {
+        if let (Some(b), Some(a), Some(op)) = (window[0].constant(), window[1].constant(), oper(2))
+        {
+            if let Some(value) = fold_binary(op, a, b) {
+                return Some((3, vec![Inst::from_value(value, pos(3))?]));
+            }
+        }
+    }
+
+    if window.len() >= 2 {
+        match (oper(1), window[0].constant()) {
+            (Some(Not), Some(value)) => {
+                let value = Value::Bool(!value.into_bool());
+                return Some((2, vec![Inst::from_value(value, pos(2))?]));
+            }
+            (Some(Drop), _) if window[0].is_pure() => return Some((2, vec![])),
+            _ => {}
+        }
+    }
+
+    // Not Not 只会把值转换成Bool, 前面已经是Bool就可以去掉
+    if window.len() >= 3
+        && window[0].is_bool()
+        && matches!((oper(1), oper(2)), (Some(Not), Some(Not)))
+    {
+        return Some((3, vec![window[0].clone()]));
+    }
+    None
+}
+
+fn has_operand(oper: Oper) -> bool {
+    use Oper::*;
+    matches!(
+        oper,
+        Local | Push | Capped | PushCap | Call | Func | Capture | CapCap | Byte | Num | Str | Sys
+    )
+}
+
+impl<'a> AsmBuilder<'a> {
+    // 常量折叠和窥孔优化, 标签是跳转目标, 不会跨过标签化简
+    pub fn optimize(&mut self) {
+        let mut insts = Vec::new();
+        let mut cmds = std::mem::take(&mut self.cmds).into_iter();
+        while let Some((cmd, pos)) = cmds.next() {
+            match cmd {
+                AsmCmd::Command(oper) => {
+                    let operand = if has_operand(oper) {
+                        cmds.next().map(|x| x.0)
+                    } else {
+                        None
+                    };
+                    insts.push(Inst::Op { oper, operand, pos });
+                }
+                cmd => insts.push(Inst::Label(cmd, pos)),
+            }
+        }
+
+        let mut index = 0;
+        while index < insts.len() {
+            if let Some((length, new)) = rewrite(&insts, index) {
+                insts.splice(index..index + length, new);
+                // 回退几步, 新的指令可能和前面的组成新的模式
+                index = index.saturating_sub(3);
+            } else {
+                index += 1;
+            }
+        }
+
+        for inst in insts {
+            match inst {
+                Inst::Label(cmd, pos) => self.cmds.push((cmd, pos)),
+                Inst::Op { oper, operand, pos } => {
+                    self.cmds.push((AsmCmd::Command(oper), pos));
+                    if let Some(operand) = operand {
+                        self.cmds.push((operand, pos));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsmBuilder<'_> {
+    // 紧跟着Ret的调用换成尾调用, Ret保留下来, 这样跳到Ret上的标签和不是函数的值都不受影响
+    pub fn mark_tail_calls(&mut self) {
+        use Oper::*;
+        for index in 0..self.cmds.len().saturating_sub(2) {
+            let tail = match self.cmds[index].0 {
+                AsmCmd::Command(Call) => TailCall,
+                AsmCmd::Command(Local) => TailLocal,
+                AsmCmd::Command(Capped) => TailCapped,
+                _ => continue,
+            };
+            if let AsmCmd::Command(Ret) = self.cmds[index + 2].0 {
+                self.cmds[index].0 = AsmCmd::Command(tail);
+            }
+        }
+    }
+
+    // 从入口开始找出所有可能执行到的代码块, 删掉其余的函数体
+    // 代码块从标签或者Ret/End之后开始, 只能通过Call/Func引用或者上一个代码块顺序执行到达
+    pub fn remove_dead_functions(&mut self) {
+        let mut starts = vec![0];
+        let mut labels = HashMap::new();
+        let mut after_end = false;
+        for (index, (cmd, _)) in self.cmds.iter().enumerate() {
+            let is_label = matches!(cmd, AsmCmd::Label(_));
+            if index != 0 && (is_label || after_end) && starts.last() != Some(&index) {
+                starts.push(index);
+            }
+            if let AsmCmd::Label(name) = cmd {
+                // 同名标签以最后一个为准, 和汇编时一致
+                labels.insert(name.as_str(), starts.len() - 1);
+            }
+            after_end = matches!(cmd, AsmCmd::Command(Oper::Ret | Oper::End));
+        }
+        let block = |index: usize| {
+            let end = starts.get(index + 1).copied().unwrap_or(self.cmds.len());
+            &self.cmds[starts[index]..end]
+        };
+
+        let mut reachable = vec![false; starts.len()];
+        let mut worklist = vec![0];
+        while let Some(index) = worklist.pop() {
+            if reachable[index] {
+                continue;
+            }
+            reachable[index] = true;
+            let cmds = block(index);
+            for (cmd, _) in cmds {
+                if let AsmCmd::Func(name) = cmd {
+                    worklist.extend(labels.get(name.as_str()));
+                }
+            }
+            let last = cmds.iter().rev().find_map(|(cmd, _)| match cmd {
+                AsmCmd::Label(_) => None,
+                cmd => Some(cmd),
+            });
+            let falls = !matches!(last, Some(AsmCmd::Command(Oper::Ret | Oper::End)));
+            if falls && index + 1 < starts.len() {
+                worklist.push(index + 1);
+            }
+        }
+
+        if reachable.iter().all(|x| *x) {
+            return;
+        }
+        let mut cmds = Vec::with_capacity(self.cmds.len());
+        for (index, reachable) in reachable.into_iter().enumerate() {
+            if reachable {
+                cmds.extend_from_slice(block(index));
+            }
+        }
+        self.cmds = cmds;
+    }
+}
@@ -1,6 +1,9 @@
 use crate::assemble::Asm;
 use crate::command::{Cmd, Oper};
+use crate::debug::{DebugInfo, LineIndex, Location};
+use crate::program::Program;
 use crate::record::Record;
+use crate::syscall::Syscalls;
 use crate::util::{uneccape, unescape};
 use colored::Color;
 use core::f64;
@@ -17,14 +20,36 @@ use std::hash::{Hash, Hasher};
 pub struct Sognasm;
 
 #[derive(Clone)]
-enum AsmCmd<'a> {
+pub(crate) enum AsmCmd<'a> {
     Number(Number),
     Str(String),
-    Func(Span<'a>),
-    Label(Span<'a>),
+    Func(Name<'a>),
+    Label(Name<'a>),
+    Syscall(Name<'a>),
     Command(Oper),
     Byte(u8),
-    List(Vec<u8>),
+    Index(usize),
+    List(Vec<usize>),
+}
+
+// 源码中的位置, file是它在AsmBuilder::files中的下标
+#[derive(Clone, Copy)]
+pub(crate) struct Pos<'a> {
+    pub span: Span<'a>,
+    pub file: usize,
+}
+
+// 标签名, 导入的文件里的标签会加上命名空间, 比如 math.square
+#[derive(Clone)]
+pub(crate) struct Name<'a> {
+    pub pos: Pos<'a>,
+    pub name: String,
+}
+
+impl Name<'_> {
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -46,32 +71,150 @@ impl Hash for Number {
 
 #[derive(Clone)]
 pub struct AsmBuilder<'a> {
-    cmds: Vec<AsmCmd<'a>>,
+    // 每条命令都带着它在源码中的位置
+    pub(crate) cmds: Vec<(AsmCmd<'a>, Pos<'a>)>,
+    // 每个源文件的内容和路径
+    files: Vec<(&'a str, String)>,
+    pos: Pos<'a>,
 }
 
 impl<'a> AsmBuilder<'a> {
+    // 生成的AsmBuilder借用了源码, 所以没法实现FromStr
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(str: &'a str) -> Result<Self, Box<Error<Rule>>> {
+        AsmBuilder::from_file("<source>", str)
+    }
+
+    // 单个文件, 不允许Import
+    pub fn from_file(path: &str, str: &'a str) -> Result<Self, Box<Error<Rule>>> {
+        let mut builder = AsmBuilder::new(str);
+        builder.push_source(path, str, None, false)?;
+        builder.scan_label(&[])?;
+        Ok(builder)
+    }
+
+    // 入口文件在最前面, 导入的文件依次接在后面
+    pub fn from_program(program: &'a Program) -> Result<Self, Box<Error<Rule>>> {
+        let root = &program.sources[0];
+        let mut builder = AsmBuilder::new(&root.content);
+        for source in &program.sources {
+            let path = source.path.to_string_lossy();
+            builder.push_source(&path, &source.content, source.namespace.as_deref(), true)?;
+        }
+        builder.scan_label(&[])?;
+        Ok(builder)
+    }
+
+    // REPL中的一行, 函数定义被移到End之后, 其余命令从头开始执行
+    // known是之前的行中定义的标签, 引用了它们的AsmBuilder只能交给Asm::extend
+    pub fn from_line(path: &str, str: &'a str, known: &[String]) -> Result<Self, Box<Error<Rule>>> {
         use Rule::*;
-        let mut builder = AsmBuilder::new();
-        let pairs = Sognasm::parse(file, str)?;
+        let mut builder = AsmBuilder::new(str);
+        builder.add_file(path, str);
+        let pairs = Sognasm::parse(file, str).map_err(|err| Box::new(err.with_path(path)))?;
+        let mut pairs = pairs.peekable();
+        let mut definitions = Vec::new();
+        let mut depth = 0;
+        while let Some(pair) = pairs.next() {
+            let start = builder.cmds.len();
+            let rule = pair.as_rule();
+            if rule == func_name && pairs.peek().map(Pair::as_rule) == Some(func_start) {
+                depth += 1;
+            }
+            let nested = depth > 0;
+            if rule == func_end {
+                depth -= 1;
+            }
+            builder.push_top(pair, path, false)?;
+            if nested {
+                definitions.extend(builder.cmds.drain(start..));
+            }
+        }
+        builder.cmds.extend(definitions);
+        builder.scan_label(known)?;
+        Ok(builder)
+    }
+
+    fn push_source(
+        &mut self,
+        path: &str,
+        str: &'a str,
+        namespace: Option<&str>,
+        imports: bool,
+    ) -> Result<(), Box<Error<Rule>>> {
+        use Rule::*;
+        let start = self.cmds.len();
+        self.add_file(path, str);
+        let pairs = Sognasm::parse(file, str).map_err(|err| Box::new(err.with_path(path)))?;
         for pair in pairs {
-            match pair.as_rule() {
-                func_name => builder.push_label(pair.as_span()),
+            self.push_top(pair, path, imports)?;
+        }
+        if let Some(namespace) = namespace {
+            self.qualify(start, namespace);
+        }
+        Ok(())
+    }
 
-                commands => {
-                    for pair in pair.into_inner().rev() {
-                        builder.push_pair(pair);
-                    }
+    // 文件最外层的一个语法单元
+    fn push_top(
+        &mut self,
+        pair: Pair<'a, Rule>,
+        path: &str,
+        imports: bool,
+    ) -> Result<(), Box<Error<Rule>>> {
+        use Rule::*;
+        self.pos.span = pair.as_span();
+        match pair.as_rule() {
+            func_name => self.push_label(pair.as_span()),
+
+            commands => {
+                for pair in pair.into_inner().rev() {
+                    self.push_pair(pair)
+                        .map_err(|err| Box::new((*err).with_path(path)))?;
                 }
+            }
 
-                func_end => builder.push_cmd(Oper::Ret),
-                EOI => builder.push_cmd(Oper::End),
+            func_start => {}
+            func_end => self.push_cmd(Oper::Ret),
+            EOI => self.push_cmd(Oper::End),
+
+            // 导入由Program处理
+            Import if imports => {}
+            Import => {
+                return Err(Box::new(
+                    Error::new_from_span(
+                        ErrorVariant::CustomError {
+                            message: "只有从文件加载程序时才能使用Import".to_owned(),
+                        },
+                        pair.as_span(),
+                    )
+                    .with_path(path),
+                ))
+            }
+
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
 
-                _ => unreachable!(),
+    // 导入的文件中, 标签和没有限定的引用都属于它自己的命名空间
+    fn qualify(&mut self, start: usize, namespace: &str) {
+        use AsmCmd::*;
+        for (cmd, _) in &mut self.cmds[start..] {
+            match cmd {
+                Label(name) => name.name = format!("{}.{}", namespace, name.name),
+                Func(name) if !name.name.contains('.') => {
+                    name.name = format!("{}.{}", namespace, name.name)
+                }
+                _ => {}
             }
         }
-        builder.scan_label()?;
-        Ok(builder)
+    }
+
+    // 之后的命令都属于这个文件
+    fn add_file(&mut self, path: &str, str: &'a str) {
+        self.pos.file = self.files.len();
+        self.files.push((str, path.to_owned()));
     }
 }
 
@@ -90,174 +233,276 @@ impl ByteCode for Vec<Cmd> {
         self.push(Cmd(oper as u8));
     }
 
+    // LEB128: 每个字节存7位, 最高位表示后面还有没有字节
     fn push_offset(&mut self, offset: usize) {
         let mut offset = offset;
-        while offset > 0xff {
-            self.push(Cmd(0xff));
-            offset -= 0xff;
+        while offset >= 0x80 {
+            self.push(Cmd((offset & 0x7f) as u8 | 0x80));
+            offset >>= 7;
         }
         self.push(Cmd(offset as u8));
     }
 }
 
 impl Asm {
+    // 和From一样, 但是所有标签都会进入函数池, 方便打印字节码
     pub fn from_builder(builder: AsmBuilder) -> (Self, Vec<String>) {
+        Asm::assemble(builder, true, true)
+    }
+
+    // 和from_builder一样, 但是保留没有用到的函数, 统计覆盖率时它们算作没有执行过
+    pub fn with_dead_functions(builder: AsmBuilder) -> (Self, Vec<String>) {
+        Asm::assemble(builder, true, false)
+    }
+
+    // 和From一样, 同时返回函数池中每个函数的名字
+    pub fn with_labels(builder: AsmBuilder) -> (Self, Vec<String>) {
+        Asm::assemble(builder, false, true)
+    }
+
+    // REPL: 把新的一行接在已有的字节码后面, 返回这一行的入口
+    // 之前的行中定义的标签都可以引用, 重新定义的标签会替换原来的函数
+    pub fn extend(&mut self, builder: AsmBuilder) -> Result<usize, Box<Error<Rule>>> {
+        builder.scan_label(self.labels())?;
+        let entry = self.cmds.len();
+        self.append(builder, true);
+        Ok(entry)
+    }
+
+    // 有调试信息时, 函数池中每个函数的名字
+    pub fn labels(&self) -> &[String] {
+        self.debug
+            .as_ref()
+            .map(|debug| debug.labels.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn assemble(mut builder: AsmBuilder, all_labels: bool, prune: bool) -> (Self, Vec<String>) {
+        if prune {
+            builder.remove_dead_functions();
+        }
+        let mut asm = Asm::new(Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        asm.append(builder, all_labels);
+        let labels = asm.debug.as_ref().unwrap().labels.clone();
+        (asm, labels)
+    }
+
+    // 常量和函数接着已有的池子编号, 已有的标签保留原来的位置
+    fn append(&mut self, mut builder: AsmBuilder, all_labels: bool) {
         use AsmCmd::*;
-        let mut string_pool = Record::new();
-        let mut number_pool = Record::new();
-        let mut function_pool = Record::new();
-        let mut label_record = HashMap::new();
-        let mut bytes = Vec::<Cmd>::new();
-        for cmd in builder.cmds {
+        builder.mark_tail_calls();
+        let debug = self.debug.get_or_insert_with(DebugInfo::default);
+        let mut string_pool = Record::from_vec(std::mem::take(&mut self.string_pool));
+        let mut number_pool = Record::from_vec(std::mem::take(&mut self.number_pool));
+        let mut function_pool = Record::from_vec(std::mem::take(&mut debug.labels));
+        let mut syscall_pool = Record::from_vec(std::mem::take(&mut self.syscall_pool));
+        let mut label_record: HashMap<String, usize> = function_pool
+            .data
+            .iter()
+            .cloned()
+            .zip(self.function_pool.iter().copied())
+            .collect();
+        let bytes = &mut self.cmds;
+        let lines = &mut debug.lines;
+        let line_indices: Vec<LineIndex> = builder
+            .files
+            .iter()
+            .map(|(str, _)| LineIndex::new(str))
+            .collect();
+        let files = debug.files.len();
+        let cmds = std::mem::take(&mut builder.cmds);
+        for (cmd, Pos { span, file }) in cmds {
+            let (line, column) = line_indices[file].line_col(span.start());
+            let location = Location {
+                file: files + file,
+                line,
+                column,
+            };
             match cmd {
                 Number(number) => bytes.push_offset(number_pool.insert(number)),
                 Str(string) => bytes.push_offset(string_pool.insert(string)),
                 Command(cmd) => bytes.push_oper(cmd),
                 Byte(byte) => bytes.push_byte(byte),
+                Index(index) => bytes.push_offset(index),
                 List(vec) => {
-                    bytes.push_byte(vec.len() as u8);
+                    bytes.push_offset(vec.len());
                     for offset in vec {
-                        bytes.push_byte(offset);
+                        bytes.push_offset(offset);
                     }
                 }
-                Func(lab) => bytes.push_offset(function_pool.insert(lab.as_str())),
-                Label(span) => {
-                    let str = span.as_str();
-                    function_pool.insert(str);
-                    label_record.insert(str, bytes.len());
+                Func(lab) => bytes.push_offset(function_pool.insert(lab.name)),
+                Syscall(name) => bytes.push_offset(syscall_pool.insert(name.name)),
+                Label(lab) => {
+                    if all_labels {
+                        function_pool.insert(lab.name.clone());
+                    }
+                    label_record.insert(lab.name, bytes.len());
                 }
             }
+            lines.resize(bytes.len(), location);
         }
         let func_vec = function_pool.into_vec();
-        println!("func: {:?}", func_vec);
-        (
-            Asm::new(
-                bytes,
-                string_pool.into_vec(),
-                number_pool.into_vec(),
-                func_vec
-                    .iter()
-                    .map(|x| *label_record.get(x).unwrap())
-                    .collect(),
-            ),
-            func_vec.into_iter().map(|str| str.to_owned()).collect(),
-        )
+        self.string_pool = string_pool.into_vec();
+        self.number_pool = number_pool.into_vec();
+        self.syscall_pool = syscall_pool.into_vec();
+        self.function_pool = func_vec
+            .iter()
+            .map(|x| *label_record.get(x).unwrap())
+            .collect();
+        debug
+            .files
+            .extend(builder.files.into_iter().map(|(_, path)| path));
+        debug.labels = func_vec;
     }
 }
 
 impl From<AsmBuilder<'_>> for Asm {
     fn from(builder: AsmBuilder) -> Self {
-        use AsmCmd::*;
-        let mut string_pool = Record::new();
-        let mut number_pool = Record::new();
-        let mut function_pool = Record::new();
-        let mut label_record = HashMap::new();
-        let mut bytes = Vec::<Cmd>::new();
-        for cmd in builder.cmds {
-            match cmd {
-                Number(number) => bytes.push_offset(number_pool.insert(number)),
-                Str(string) => bytes.push_offset(string_pool.insert(string)),
-                Command(cmd) => bytes.push_oper(cmd),
-                Byte(byte) => bytes.push_byte(byte),
-                List(vec) => {
-                    bytes.push_byte(vec.len() as u8);
-                    for offset in vec {
-                        bytes.push_byte(offset);
-                    }
-                }
-                Func(lab) => bytes.push_offset(function_pool.insert(lab.as_str())),
-                Label(span) => {
-                    label_record.insert(span.as_str(), bytes.len());
-                }
-            }
-        }
-
-        Asm::new(
-            bytes,
-            string_pool.into_vec(),
-            number_pool.into_vec(),
-            function_pool
-                .into_vec()
-                .into_iter()
-                .map(|x| *label_record.get(&x).unwrap())
-                .collect(),
-        )
+        Asm::assemble(builder, false, true).0
     }
 }
 
 impl<'a> AsmBuilder<'a> {
-    fn new() -> Self {
-        AsmBuilder { cmds: Vec::new() }
+    fn new(str: &'a str) -> Self {
+        AsmBuilder {
+            cmds: Vec::new(),
+            files: Vec::new(),
+            pos: Pos {
+                span: Span::new(str, 0, 0).unwrap(),
+                file: 0,
+            },
+        }
+    }
+
+    fn push(&mut self, cmd: AsmCmd<'a>) {
+        self.cmds.push((cmd, self.pos));
     }
 
     fn push_str(&mut self, str: String) {
         use AsmCmd::*;
-        self.cmds.push(Str(str))
+        self.push(Str(str))
     }
 
     fn push_label(&mut self, label: Span<'a>) {
         use AsmCmd::*;
-        self.cmds.push(Label(label));
+        self.push(Label(Name {
+            pos: Pos {
+                span: label,
+                ..self.pos
+            },
+            name: label.as_str().to_owned(),
+        }));
     }
 
     fn push_func(&mut self, label: Span<'a>) {
         use AsmCmd::*;
-        self.cmds.push(Func(label));
+        self.push(Func(Name {
+            pos: Pos {
+                span: label,
+                ..self.pos
+            },
+            name: label.as_str().to_owned(),
+        }));
     }
-    fn push_list(&mut self, list: Vec<u8>) {
+    fn push_list(&mut self, list: Vec<usize>) {
         use AsmCmd::*;
-        self.cmds.push(List(list));
+        self.push(List(list));
     }
 
     fn push_byte(&mut self, byte: u8) {
         use AsmCmd::*;
-        self.cmds.push(Byte(byte))
+        self.push(Byte(byte))
+    }
+
+    fn push_index(&mut self, index: usize) {
+        use AsmCmd::*;
+        self.push(Index(index))
     }
 
     fn push_cmd(&mut self, oper: Oper) {
         use AsmCmd::*;
-        self.cmds.push(Command(oper));
+        self.push(Command(oper));
     }
 
     fn push_number(&mut self, number: f64) {
-        self.cmds.push(AsmCmd::Number(Number(number)))
+        self.push(AsmCmd::Number(Number(number)))
     }
 
-    fn scan_label(&mut self) -> Result<(), Box<Error<Rule>>> {
+    // known是已经汇编好的标签
+    fn scan_label(&self, known: &[String]) -> Result<(), Box<Error<Rule>>> {
         use AsmCmd::*;
-        let mut label_record = HashSet::new();
-        let mut unknown_record = HashMap::new();
-        for command in &self.cmds {
-            match command {
-                Func(name) => {
-                    unknown_record.insert(name.as_str(), name);
-                }
-                Label(name) => {
-                    label_record.insert(name.as_str());
-                }
-                _ => {}
+        let mut label_record: HashSet<&str> = known.iter().map(String::as_str).collect();
+        for (command, _) in &self.cmds {
+            if let Label(name) = command {
+                label_record.insert(name.as_str());
             }
         }
 
-        for v in label_record {
-            unknown_record.remove(v);
+        let unknown = self.cmds.iter().find_map(|(command, _)| match command {
+            Func(name) if !label_record.contains(name.as_str()) => Some(name),
+            _ => None,
+        });
+        match unknown {
+            None => Ok(()),
+            Some(name) => {
+                let path = &self.files[name.pos.file].1;
+                Err(Box::new(
+                    Error::new_from_span(
+                        ErrorVariant::CustomError {
+                            message: "未知的标签".to_owned(),
+                        },
+                        name.pos.span,
+                    )
+                    .with_path(path),
+                ))
+            }
         }
+    }
 
-        if unknown_record.is_empty() {
-            Ok(())
-        } else {
-            Err(Box::new(Error::new_from_span(
-                ErrorVariant::CustomError {
-                    message: "未知的标签".to_owned(),
-                },
-                **unknown_record.iter().next().unwrap().1,
-            )))
+    // 汇编之前检查Sys用到的名字都注册过了
+    pub fn check_syscalls(&self, syscalls: &Syscalls) -> Result<(), Box<Error<Rule>>> {
+        let unknown = self.cmds.iter().find_map(|(command, _)| match command {
+            AsmCmd::Syscall(name) if !syscalls.contains(name.as_str()) => Some(name),
+            _ => None,
+        });
+        match unknown {
+            None => Ok(()),
+            Some(name) => {
+                let path = &self.files[name.pos.file].1;
+                Err(Box::new(
+                    Error::new_from_span(
+                        ErrorVariant::CustomError {
+                            message: "未知的系统调用".to_owned(),
+                        },
+                        name.pos.span,
+                    )
+                    .with_path(path),
+                ))
+            }
         }
     }
 
     pub fn display(&self, index: usize) {
+        let line_indices: Vec<LineIndex> = self
+            .files
+            .iter()
+            .map(|(str, _)| LineIndex::new(str))
+            .collect();
         let mut counter = 0;
-        for cmd in &self.cmds {
+        let mut last = None;
+        for (cmd, pos) in &self.cmds {
+            // 每一行源码另起一行, 前面标出文件和行号
+            let Pos { span, file } = *pos;
+            let (line, _) = line_indices[file].line_col(span.start());
+            if !matches!(cmd, AsmCmd::Label(_)) && last != Some((file, line)) {
+                if counter != 0 || last.is_some() {
+                    println!();
+                }
+                let location = format!("{}:{}", self.files[file].1, line);
+                print!("\x1b[0;2m{}\x1b[0m", location);
+                counter = 0;
+                last = Some((file, line));
+            }
             counter += 1;
             if counter % 13 == 0 {
                 print!("\n  ");
@@ -270,10 +515,10 @@ impl<'a> AsmBuilder<'a> {
             match cmd {
                 AsmCmd::Number(number) => print!("{}", number.0),
                 AsmCmd::Str(str) => {
-                    let str = if str.len() > 5 {
-                        format!("{}..", &str[..5])
-                    } else {
-                        str.to_owned()
+                    // 按字符截断, 中文不能从中间切开
+                    let str = match str.char_indices().nth(5) {
+                        Some((end, _)) => format!("{}..", &str[..end]),
+                        None => str.to_owned(),
                     };
                     print!("\"{}\",", str);
                 }
@@ -289,17 +534,19 @@ impl<'a> AsmBuilder<'a> {
                         print!("'{}'", *byte as char);
                     };
                 }
+                AsmCmd::Index(index) => print!("{}", index),
                 AsmCmd::List(list) => {
                     let list = list
                         .iter()
-                        .map(u8::to_string)
+                        .map(usize::to_string)
                         .collect::<Vec<String>>()
                         .join(" ");
                     print!("[{}]", list);
                 }
-                AsmCmd::Func(name) => print!("{}", name.as_str()),
+                AsmCmd::Func(name) | AsmCmd::Syscall(name) => print!("{}", name.as_str()),
                 AsmCmd::Label(name) => {
                     counter = 0;
+                    last = None;
                     print!("\n\x1b[0m{}:\n", name.as_str())
                 }
             }
@@ -307,14 +554,23 @@ impl<'a> AsmBuilder<'a> {
         println!("\x1b[0m")
     }
 
-    fn push_pair(&mut self, pair: Pair<'a, Rule>) {
-        fn get_offset(pair: Pair<'_, Rule>) -> u8 {
-            pair.into_inner()
-                .next()
-                .unwrap()
-                .as_str()
-                .parse::<u8>()
-                .unwrap()
+    fn push_pair(&mut self, pair: Pair<'a, Rule>) -> Result<(), Box<Error<Rule>>> {
+        self.pos.span = pair.as_span();
+        fn custom(message: &str, span: Span) -> Box<Error<Rule>> {
+            let message = message.to_owned();
+            Box::new(Error::new_from_span(
+                ErrorVariant::CustomError { message },
+                span,
+            ))
+        }
+        // 语法只保证是一串数字, 太大的下标要在这里报错
+        fn parse_offset(pair: Pair<'_, Rule>) -> Result<usize, Box<Error<Rule>>> {
+            pair.as_str()
+                .parse::<usize>()
+                .map_err(|_| custom("下标太大了", pair.as_span()))
+        }
+        fn get_offset(pair: Pair<'_, Rule>) -> Result<usize, Box<Error<Rule>>> {
+            parse_offset(pair.into_inner().next().unwrap())
         }
         use Rule::*;
         let rule = pair.as_rule();
@@ -361,13 +617,15 @@ impl<'a> AsmBuilder<'a> {
                 self.push_cmd(Oper::NewList);
                 self.push_cmd(Oper::Collect)
             }
+            NewList => self.push_cmd(Oper::NewList),
+            Collect => self.push_cmd(Oper::Collect),
 
             Capture => {
                 self.push_cmd(Oper::Capture);
                 let caplist = pair.into_inner();
                 let mut list = vec![];
                 for capped in caplist {
-                    list.push(capped.as_str().parse::<u8>().unwrap());
+                    list.push(parse_offset(capped)?);
                 }
                 self.push_list(list);
             }
@@ -377,29 +635,29 @@ impl<'a> AsmBuilder<'a> {
                 let caplist = pair.into_inner();
                 let mut list = vec![];
                 for capped in caplist {
-                    list.push(capped.as_str().parse::<u8>().unwrap());
+                    list.push(parse_offset(capped)?);
                 }
                 self.push_list(list);
             }
 
             Local => {
                 self.push_cmd(Oper::Local);
-                self.push_byte(get_offset(pair))
+                self.push_index(get_offset(pair)?)
             }
 
             Push => {
                 self.push_cmd(Oper::Push);
-                self.push_byte(get_offset(pair))
+                self.push_index(get_offset(pair)?)
             }
 
             Capped => {
                 self.push_cmd(Oper::Capped);
-                self.push_byte(get_offset(pair))
+                self.push_index(get_offset(pair)?)
             }
 
             PushCapped => {
                 self.push_cmd(Oper::PushCap);
-                self.push_byte(get_offset(pair))
+                self.push_index(get_offset(pair)?)
             }
 
             Num => {
@@ -422,37 +680,59 @@ impl<'a> AsmBuilder<'a> {
 
             Byte => {
                 self.push_cmd(Oper::Byte);
-                let value =
-                    u8::from_str_radix(pair.as_str(), 16).expect("16进制字节解析失败, 这不太可能");
+                // 去掉结尾的H, 语法保证最多是0ff
+                let digits = pair.as_str().trim_end_matches('H');
+                let value = u8::from_str_radix(digits, 16).expect("16进制字节解析失败, 这不太可能");
                 self.push_byte(value);
             }
 
             Char => {
                 self.push_cmd(Oper::Byte);
-                self.push_byte(uneccape(pair.into_inner().as_str()));
+                let span = pair.as_span();
+                let byte = uneccape(pair.into_inner().as_str())
+                    .ok_or_else(|| custom("字符只能是一个字节", span))?;
+                self.push_byte(byte);
             }
 
             Str => {
                 self.push_cmd(Oper::Str);
-                let str = pair.into_inner().next().expect("解析字符串失败").as_str();
-                self.push_str(unescape(str));
+                let str = pair.into_inner().next().expect("解析字符串失败");
+                let str =
+                    unescape(str.as_str()).ok_or_else(|| custom("不认识的转义", str.as_span()))?;
+                self.push_str(str);
             }
             End => self.push_cmd(Oper::End),
+            Try => self.push_cmd(Oper::Try),
+            Throw => self.push_cmd(Oper::Throw),
+
+            Sys => {
+                self.push_cmd(Oper::Sys);
+                let name = pair.into_inner().next().unwrap().as_span();
+                self.push(AsmCmd::Syscall(Name {
+                    pos: Pos {
+                        span: name,
+                        ..self.pos
+                    },
+                    name: name.as_str().to_owned(),
+                }));
+            }
             _ => unreachable!(),
         }
+        Ok(())
     }
 }
 
 pub fn map_color(cmd: &Oper) -> String {
     use Oper::*;
     let fg = match cmd {
-        Call | Add | Sub | SubBy | Div | DivBy | Mul | Mod | ModBy | Xor | BitOr | BitAnd | And
-        | Or | Not | Lt | Gt | Eq | Le | Ge => Color::Cyan,
-        If | Type | Local | Capped | Push | Pop | Drop | Ret | End => Color::Red,
+        Call | TailCall | Add | Sub | SubBy | Div | DivBy | Mul | Mod | ModBy | Xor | BitOr
+        | BitAnd | And | Or | Not | Lt | Gt | Eq | Le | Ge => Color::Cyan,
+        If | Type | Local | Capped | TailLocal | TailCapped | Push | Pop | Drop | Ret | End
+        | Try | Throw => Color::Red,
         Capture | CapCap => Color::Yellow,
         PushCap | NewList | Collect | Insert | Append | Concat | Length | Empty | Head | Rest
         | Input => Color::Blue,
-        Output | Print | Flush => Color::Magenta,
+        Output | Print | Flush | Sys => Color::Magenta,
         Byte | Num | Func | Str | True | False => Color::Green,
         _ => unreachable!(),
     }
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::assemble::Asm;
+use crate::command::{Cmd, Oper};
+use crate::runtime::Runtime;
+
+// 调用树上的一个节点, 同一个函数从不同的调用链进入时是不同的节点
+struct Node {
+    parent: usize,
+    // None是最外层
+    func: Option<usize>,
+    instructions: u64,
+    time: Duration,
+}
+
+// 在Runtime::execute的trace里收集数据, 结束之后整理成Profile
+pub(crate) struct Profiler {
+    opers: [u64; 256],
+    nodes: Vec<Node>,
+    children: HashMap<(usize, usize), usize>,
+    // 当前调用栈对应的节点
+    node: usize,
+    depth: usize,
+    calls: HashMap<usize, u64>,
+    max_stack: usize,
+    max_depth: usize,
+    start: Instant,
+    last: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    // 只算函数自己执行的指令
+    pub instructions: u64,
+    // 加上它调用的函数, 递归调用只算一次
+    pub total_instructions: u64,
+    pub time: Duration,
+    pub total_time: Duration,
+}
+
+// 一次运行的统计
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub instructions: u64,
+    pub time: Duration,
+    pub max_stack: usize,
+    pub max_depth: usize,
+    // 按次数从多到少排列
+    pub opers: Vec<(Oper, u64)>,
+    // 按自身用时从多到少排列
+    pub functions: Vec<FunctionProfile>,
+    // 调用链和在它上面执行的指令数
+    pub stacks: Vec<(Vec<String>, u64)>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Profiler {
+            opers: [0; 256],
+            nodes: vec![Node {
+                parent: 0,
+                func: None,
+                instructions: 0,
+                time: Duration::ZERO,
+            }],
+            children: HashMap::new(),
+            node: 0,
+            depth: 0,
+            calls: HashMap::new(),
+            max_stack: 0,
+            max_depth: 0,
+            start: now,
+            last: now,
+        }
+    }
+
+    fn child(&mut self, parent: usize, func: usize) -> usize {
+        let nodes = &mut self.nodes;
+        *self.children.entry((parent, func)).or_insert_with(|| {
+            nodes.push(Node {
+                parent,
+                func: Some(func),
+                instructions: 0,
+                time: Duration::ZERO,
+            });
+            nodes.len() - 1
+        })
+    }
+
+    // 在执行oper之前调用, 上一条指令的用时算在上一次的节点上
+    pub fn step(&mut self, runtime: &Runtime, oper: Oper) {
+        let now = Instant::now();
+        self.nodes[self.node].time += now - self.last;
+        self.last = now;
+
+        let machine = runtime.machine();
+        let frames = &machine.frames;
+        let top = frames.last().map(|frame| frame.func);
+        // 每条指令最多进入或者退出一层, 尾调用只换掉最里层的函数
+        if frames.len() == self.depth + 1 {
+            self.node = self.child(self.node, top.unwrap());
+        } else if frames.len() + 1 == self.depth {
+            self.node = self.nodes[self.node].parent;
+        } else if frames.len() != self.depth {
+            self.node = 0;
+            for frame in frames {
+                self.node = self.child(self.node, frame.func);
+            }
+        } else if self.nodes[self.node].func != top {
+            let parent = self.nodes[self.node].parent;
+            self.node = self.child(parent, top.unwrap());
+        }
+        self.depth = frames.len();
+
+        // 只有调用才会跳到函数入口
+        if let Some(func) = top.filter(|func| *func == runtime.current()) {
+            *self.calls.entry(func).or_default() += 1;
+        }
+        self.nodes[self.node].instructions += 1;
+        self.opers[oper as usize] += 1;
+        self.max_stack = self.max_stack.max(machine.stack.len());
+        self.max_depth = self.max_depth.max(frames.len());
+    }
+
+    pub fn finish(mut self, asm: &Asm) -> Profile {
+        let now = Instant::now();
+        self.nodes[self.node].time += now - self.last;
+        let name = |func: Option<usize>| match func {
+            None => "<main>".to_owned(),
+            Some(ip) => asm.function_name(ip),
+        };
+
+        // 子节点总在父节点之后创建, 倒着加一遍就得到整棵子树的总和
+        let mut totals: Vec<(u64, Duration)> = self
+            .nodes
+            .iter()
+            .map(|node| (node.instructions, node.time))
+            .collect();
+        for index in (1..self.nodes.len()).rev() {
+            let parent = self.nodes[index].parent;
+            let (instructions, time) = totals[index];
+            totals[parent].0 += instructions;
+            totals[parent].1 += time;
+        }
+
+        let mut children = vec![Vec::new(); self.nodes.len()];
+        for (index, node) in self.nodes.iter().enumerate().skip(1) {
+            children[node.parent].push(index);
+        }
+        let mut functions: HashMap<Option<usize>, FunctionProfile> = HashMap::new();
+        // 调用链上已经有同一个函数时, 它的总计已经包含了这棵子树
+        let mut active: HashMap<Option<usize>, usize> = HashMap::new();
+        let mut stack = vec![(0, true)];
+        while let Some((index, enter)) = stack.pop() {
+            let node = &self.nodes[index];
+            let count = active.entry(node.func).or_default();
+            if !enter {
+                *count -= 1;
+                continue;
+            }
+            let profile = functions
+                .entry(node.func)
+                .or_insert_with(|| FunctionProfile {
+                    name: name(node.func),
+                    calls: node
+                        .func
+                        .map_or(1, |func| self.calls.get(&func).copied().unwrap_or(0)),
+                    instructions: 0,
+                    total_instructions: 0,
+                    time: Duration::ZERO,
+                    total_time: Duration::ZERO,
+                });
+            profile.instructions += node.instructions;
+            profile.time += node.time;
+            if *count == 0 {
+                profile.total_instructions += totals[index].0;
+                profile.total_time += totals[index].1;
+            }
+            *count += 1;
+            stack.push((index, false));
+            stack.extend(children[index].iter().map(|child| (*child, true)));
+        }
+        let mut functions: Vec<FunctionProfile> = functions.into_values().collect();
+        functions.sort_by(|a, b| b.time.cmp(&a.time).then(a.name.cmp(&b.name)));
+
+        let mut stacks = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.instructions == 0 {
+                continue;
+            }
+            let mut path = Vec::new();
+            let mut index = index;
+            loop {
+                path.push(name(self.nodes[index].func));
+                if index == 0 {
+                    break;
+                }
+                index = self.nodes[index].parent;
+            }
+            path.reverse();
+            stacks.push((path, node.instructions));
+        }
+
+        let mut opers: Vec<(Oper, u64)> = self
+            .opers
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(oper, count)| (Oper::from(&Cmd(oper as u8)), *count))
+            .collect();
+        opers.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+        Profile {
+            instructions: totals[0].0,
+            time: now - self.start,
+            max_stack: self.max_stack,
+            max_depth: self.max_depth,
+            opers,
+            functions,
+            stacks,
+        }
+    }
+}
+
+// 中文字符在终端里占两格
+fn pad(text: &str, width: usize, left: bool) -> String {
+    let used: usize = text
+        .chars()
+        .map(|c| if (c as u32) < 0x1100 { 1 } else { 2 })
+        .sum();
+    let space = " ".repeat(width.saturating_sub(used));
+    if left {
+        format!("{}{}", text, space)
+    } else {
+        format!("{}{}", space, text)
+    }
+}
+
+impl Profile {
+    // 打印给人看的表格
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "指令数 {}, 用时 {:.3?}, 最大栈深度 {}, 最深调用 {}",
+            self.instructions, self.time, self.max_stack, self.max_depth
+        );
+        let row = |out: &mut String, cells: [&str; 6]| {
+            let _ = write!(out, "{}", pad(cells[0], 20, true));
+            for cell in &cells[1..] {
+                let _ = write!(out, " {}", pad(cell, 12, false));
+            }
+            out.push('\n');
+        };
+        out.push('\n');
+        row(
+            &mut out,
+            [
+                "函数",
+                "调用次数",
+                "指令(自身)",
+                "指令(总计)",
+                "用时(自身)",
+                "用时(总计)",
+            ],
+        );
+        for function in &self.functions {
+            row(
+                &mut out,
+                [
+                    &function.name,
+                    &function.calls.to_string(),
+                    &function.instructions.to_string(),
+                    &function.total_instructions.to_string(),
+                    &format!("{:.3?}", function.time),
+                    &format!("{:.3?}", function.total_time),
+                ],
+            );
+        }
+        let _ = writeln!(
+            out,
+            "\n{} {}",
+            pad("指令", 20, true),
+            pad("次数", 12, false)
+        );
+        for (oper, count) in &self.opers {
+            let _ = writeln!(
+                out,
+                "{} {}",
+                pad(&format!("{:?}", oper), 20, true),
+                pad(&count.to_string(), 12, false)
+            );
+        }
+        out
+    }
+
+    // 火焰图工具用的折叠调用栈, 每行是调用链和在它上面执行的指令数
+    pub fn folded(&self) -> String {
+        let mut out = String::new();
+        for (path, count) in &self.stacks {
+            let _ = writeln!(out, "{} {}", path.join(";"), count);
+        }
+        out
+    }
+}
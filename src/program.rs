@@ -0,0 +1,139 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pest::error::{Error, ErrorVariant};
+use pest::{Parser, Span};
+
+use crate::parser::{Rule, Sognasm};
+use crate::util::{is_identifier, unescape};
+
+// 程序由入口文件和它直接或间接导入的文件组成
+pub struct Source {
+    pub path: PathBuf,
+    // 入口文件没有命名空间, 导入的文件以文件名为命名空间
+    pub namespace: Option<String>,
+    pub content: String,
+}
+
+pub struct Program {
+    // 第一个是入口文件
+    pub sources: Vec<Source>,
+}
+
+struct Loader {
+    search: Vec<PathBuf>,
+    sources: Vec<Source>,
+    loaded: HashSet<PathBuf>,
+    // 正在加载的文件, 用来检测循环导入
+    stack: Vec<PathBuf>,
+    namespaces: HashMap<String, PathBuf>,
+}
+
+fn error(message: String, span: Span, path: &Path) -> Box<Error<Rule>> {
+    Box::new(
+        Error::new_from_span(ErrorVariant::CustomError { message }, span)
+            .with_path(&path.to_string_lossy()),
+    )
+}
+
+impl Loader {
+    // 先找导入它的文件所在的目录, 再依次找搜索路径
+    fn resolve(&self, name: &str, from: &Path) -> Option<PathBuf> {
+        let dir = from.parent().unwrap_or(Path::new(""));
+        std::iter::once(dir)
+            .chain(self.search.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+
+    fn load(
+        &mut self,
+        path: PathBuf,
+        namespace: Option<String>,
+        content: String,
+    ) -> Result<(), Box<Error<Rule>>> {
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        self.loaded.insert(canonical.clone());
+        self.stack.push(canonical);
+
+        let pairs = Sognasm::parse(Rule::file, &content)
+            .map_err(|err| Box::new(err.with_path(&path.to_string_lossy())))?;
+        for pair in pairs.filter(|pair| pair.as_rule() == Rule::Import) {
+            let span = pair.as_span();
+            let name = pair.into_inner().next().unwrap().into_inner().as_str();
+            let Some(name) = unescape(name) else {
+                return Err(error("不认识的转义".to_owned(), span, &path));
+            };
+            let Some(import) = self.resolve(&name, &path) else {
+                return Err(error(format!("找不到导入的文件 {}", name), span, &path));
+            };
+            let canonical = fs::canonicalize(&import).unwrap_or_else(|_| import.clone());
+            if self.stack.contains(&canonical) {
+                return Err(error(format!("循环导入 {}", name), span, &path));
+            }
+            if self.loaded.contains(&canonical) {
+                continue;
+            }
+
+            let stem = import.file_stem().map(|x| x.to_string_lossy().into_owned());
+            let Some(stem) = stem.filter(|x| is_identifier(x)) else {
+                return Err(error(
+                    format!("文件名 {} 不能作为命名空间", name),
+                    span,
+                    &path,
+                ));
+            };
+            if let Some(other) = self.namespaces.get(&stem) {
+                return Err(error(
+                    format!("命名空间 {} 和 {} 重复", stem, other.display()),
+                    span,
+                    &path,
+                ));
+            }
+            self.namespaces.insert(stem.clone(), canonical);
+
+            let content = match fs::read_to_string(&import) {
+                Ok(content) => content,
+                Err(err) => return Err(error(format!("无法读取 {}: {}", name, err), span, &path)),
+            };
+            self.load(import, Some(stem), content)?;
+        }
+
+        self.stack.pop();
+        self.sources.push(Source {
+            path,
+            namespace,
+            content,
+        });
+        Ok(())
+    }
+}
+
+impl Program {
+    // 从入口文件开始加载所有导入的文件, 搜索路径之后还会找环境变量SOGNASM_PATH
+    pub fn load(
+        path: impl Into<PathBuf>,
+        content: String,
+        search: &[PathBuf],
+    ) -> Result<Self, Box<Error<Rule>>> {
+        let mut search = search.to_vec();
+        if let Some(paths) = env::var_os("SOGNASM_PATH") {
+            search.extend(env::split_paths(&paths));
+        }
+        let mut loader = Loader {
+            search,
+            sources: Vec::new(),
+            loaded: HashSet::new(),
+            stack: Vec::new(),
+            namespaces: HashMap::new(),
+        };
+        loader.load(path.into(), None, content)?;
+        // 文件是在导入的文件都加载完之后才加入的, 入口文件在最后
+        loader.sources.reverse();
+        Ok(Program {
+            sources: loader.sources,
+        })
+    }
+}
@@ -0,0 +1,53 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::ErrorMessage;
+use crate::syscall::Syscalls;
+use crate::value::Value;
+
+// SplitMix64, 同一个种子总是得到同样的序列
+struct Random(Cell<u64>);
+
+impl Random {
+    fn next(&self) -> u64 {
+        let state = self.0.get().wrapping_add(0x9e3779b97f4a7c15);
+        self.0.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // [0, 1)之间, 取高53位正好填满f64的尾数
+    fn float(&self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// 没有指定种子时用当前时间
+pub(crate) fn seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos() as u64)
+}
+
+pub(crate) fn register(syscalls: &mut Syscalls, seed: u64) {
+    let random = Rc::new(Random(Cell::new(seed)));
+
+    let state = random.clone();
+    syscalls.register("rand", move |machine| {
+        machine.push(Value::Number(state.float()))
+    });
+
+    // [0, n)之间的整数
+    syscalls.register("rand.int", move |machine| {
+        let bound = machine.pop()?.into_number().floor();
+        if !(bound >= 1.0 && bound <= (1u64 << 53) as f64) {
+            let message = format!("rand.int的上限必须是正整数, 而不是{}", bound);
+            return Err(ErrorMessage::Syscall(message));
+        }
+        let value = (random.float() * bound).floor();
+        machine.push(Value::Number(value))
+    });
+}
@@ -16,11 +16,14 @@ where
     T: std::hash::Hash,
     T: Clone,
 {
-    pub fn new() -> Self {
-        Record {
-            record: HashMap::new(),
-            data: Vec::new(),
-        }
+    // 接着已有的数据继续编号
+    pub fn from_vec(data: Vec<T>) -> Self {
+        let record = data
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (value.clone(), index))
+            .collect();
+        Record { record, data }
     }
 
     pub fn insert(&mut self, value: T) -> usize {
@@ -0,0 +1,128 @@
+use colored::Colorize;
+use sognasm::{Asm, AsmBuilder, Machine, Syscalls};
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::argus::Arguments;
+
+// 这一行里没有闭合的花括号数量, 字符串, 字符和注释里的不算
+fn braces(line: &str) -> isize {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(char) = chars.next() {
+        match (quote, char) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(end), char) if char == end => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(char),
+            (None, ';') => break,
+            (None, '{') => depth += 1,
+            (None, '}') => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+pub struct Repl<'a> {
+    arguments: &'a Arguments,
+    // 之前所有行汇编出来的代码, 函数定义一直留在这里
+    asm: Asm,
+    machine: Machine,
+    // 随机数和计时在各行之间接着用
+    syscalls: Syscalls,
+    interrupt: Arc<AtomicBool>,
+    count: usize,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(arguments: &'a Arguments, interrupt: Arc<AtomicBool>) -> Self {
+        Repl {
+            arguments,
+            asm: Asm::new(Vec::new(), Vec::new(), Vec::new(), Vec::new()),
+            machine: Machine::new(),
+            syscalls: crate::syscalls(arguments),
+            interrupt,
+            count: 0,
+        }
+    }
+
+    // 花括号没有闭合时继续读下一行, 读到文件结尾退出
+    pub fn run(mut self) {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        let mut input = String::new();
+        let mut depth = 0;
+        loop {
+            print!("{}", if input.is_empty() { "> " } else { ". " });
+            io::stdout().flush().ok();
+            let Some(Ok(line)) = lines.next() else {
+                break;
+            };
+            depth += braces(&line);
+            input.push_str(&line);
+            input.push('\n');
+            if depth > 0 {
+                continue;
+            }
+            depth = 0;
+            let source = std::mem::take(&mut input);
+            if !source.trim().is_empty() {
+                self.eval(&source);
+            }
+        }
+        println!();
+    }
+
+    // 出错时栈和局部变量保持执行这一行之前的样子
+    fn eval(&mut self, source: &str) {
+        self.count += 1;
+        let path = format!("<repl:{}>", self.count);
+        let mut builder = match AsmBuilder::from_line(&path, source, self.asm.labels()) {
+            Ok(builder) => builder,
+            Err(err) => return eprintln!("{}", err),
+        };
+        if let Err(err) = builder.check_syscalls(&crate::host_syscalls(self.arguments)) {
+            return eprintln!("{}", err);
+        }
+        if self.arguments.is_optimize() {
+            builder.optimize();
+        }
+
+        let backup = self.asm.clone();
+        let entry = match self.asm.extend(builder) {
+            Ok(entry) => entry,
+            Err(err) => return eprintln!("{}", err),
+        };
+        if let Err(diagnostics) = self.asm.verify() {
+            for diagnostic in diagnostics {
+                eprintln!("{} {}", "[error]".red(), diagnostic);
+            }
+            self.asm = backup;
+            return;
+        }
+
+        self.interrupt.store(false, Ordering::Relaxed);
+        let mut runtime = crate::runtime(&self.asm, self.arguments)
+            .with_machine(self.machine.clone())
+            .with_syscalls(std::mem::take(&mut self.syscalls))
+            .with_interrupt(self.interrupt.clone());
+        runtime.jmp(entry);
+        let result = runtime.execute(|_, _| {});
+        self.syscalls = runtime.take_syscalls();
+        match result {
+            Ok(status) => {
+                self.machine = runtime.into_machine();
+                self.machine.stack = status.stack;
+                // End可能在函数里面结束了这一行
+                self.machine.return_to_top();
+            }
+            Err(err) => crate::report(&err),
+        }
+        println!("{}", &self.machine);
+    }
+}
@@ -1,49 +1,90 @@
-use crate::error::ErrorMessage;
+use crate::coverage::Coverage;
 use crate::error::ErrorMessage::*;
-use crate::machine::Machine;
+use crate::error::{ErrorMessage, RuntimeError, TraceFrame};
+use crate::machine::{Limits, Machine};
+use crate::profile::{Profile, Profiler};
+use crate::syscall::Syscalls;
 use std::collections::LinkedList;
 use std::fmt;
 use std::io::Read;
 use std::io::Write;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::assemble::Asm;
 use crate::command::Oper;
 use crate::value::{Closure, Value};
 
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub struct Runtime<'a> {
     index: usize,
+    // 当前指令的起始位置
+    current: usize,
     codes: &'a Asm,
-    machine: Machine<'a>,
+    // 字符串常量只在创建时复制一次, 之后Str指令只增加引用计数
+    strings: Vec<Rc<str>>,
+    machine: Machine,
     writer: Box<dyn Write>,
+    reader: Box<dyn Read>,
+    syscalls: Syscalls,
+    // 剩下还能执行的指令数, None表示不限
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    steps: u64,
 }
 
-fn read() -> Option<u8> {
-    let mut buffer = [0u8; 1];
-    match std::io::stdin().read_exact(&mut buffer) {
-        Ok(_) => Some(buffer[0]),
-        Err(_) => None,
-    }
+// 每执行这么多条指令检查一次有没有超时
+const CLOCK_INTERVAL: u64 = 1024;
+
+// 输入结束之后Input放到栈上的值, 和任何字节都不相等
+pub const EOF: f64 = -1.0;
+
+// 程序正常结束时的状态
+#[derive(Debug)]
+pub struct ExitStatus {
+    // 结束时栈上剩下的值
+    pub stack: Vec<Value>,
+}
+
+enum State {
+    Running,
+    Finished,
 }
 
 impl<'a> Runtime<'a> {
-    pub fn new(asm: &'a mut Asm) -> Runtime<'a> {
-        Runtime {
-            index: 0,
-            codes: asm,
-            machine: Machine::new(),
-            writer: Box::new(std::io::stdout()),
-        }
+    pub fn new(asm: &'a Asm) -> Runtime<'a> {
+        Runtime::new_with_writer(asm, Box::new(std::io::stdout()))
     }
 
-    pub fn new_with_writer(asm: &'a mut Asm, writer: Box<dyn Write>) -> Runtime<'a> {
+    pub fn new_with_writer(asm: &'a Asm, writer: Box<dyn Write>) -> Runtime<'a> {
         Runtime {
             index: 0,
+            current: 0,
             codes: asm,
+            strings: asm
+                .string_pool
+                .iter()
+                .map(|str| Rc::from(str.as_str()))
+                .collect(),
             machine: Machine::new(),
             writer,
+            reader: Box::new(std::io::stdin()),
+            syscalls: Syscalls::new(),
+            fuel: None,
+            timeout: None,
+            steps: 0,
+        }
+    }
+
+    // 读到结尾时返回None
+    fn read(&mut self) -> Result<Option<u8>, ErrorMessage> {
+        let mut buffer = [0u8; 1];
+        match self.reader.read_exact(&mut buffer) {
+            Ok(_) => Ok(Some(buffer[0])),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(_) => Err(ReadErr),
         }
     }
 
@@ -53,95 +94,336 @@ impl<'a> Runtime<'a> {
             .map_err(|_| ErrorMessage::PrintErr)
     }
 
+    // Input从这里读取, 默认是标准输入
+    pub fn with_reader(mut self, reader: Box<dyn Read>) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    // 默认没有任何系统调用
+    pub fn with_syscalls(mut self, syscalls: Syscalls) -> Self {
+        self.syscalls = syscalls;
+        self
+    }
+
+    pub fn with_syscall<F>(mut self, name: &str, syscall: F) -> Self
+    where
+        F: FnMut(&mut Machine) -> Result<(), ErrorMessage> + 'static,
+    {
+        self.syscalls.register(name, syscall);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.machine.limits = limits;
+        self
+    }
+
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    // 超时从开始执行时算起
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // 被设置时在下一条指令之前停下, 比如Ctrl-C
+    pub fn with_interrupt(mut self, interrupt: Arc<AtomicBool>) -> Self {
+        self.machine.interrupt = Some(interrupt);
+        self
+    }
+
+    // 接着之前的状态执行, 比如REPL的上一行, 限制和中断仍然以这个Runtime为准
+    pub fn with_machine(mut self, mut machine: Machine) -> Self {
+        machine.limits = self.machine.limits;
+        machine.interrupt = self.machine.interrupt.take();
+        machine.deadline = self.machine.deadline;
+        self.machine = machine;
+        self
+    }
+
+    // 拿回系统调用, 里面的状态比如随机数可以留给下一个Runtime
+    pub fn take_syscalls(&mut self) -> Syscalls {
+        std::mem::take(&mut self.syscalls)
+    }
+
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn asm(&self) -> &'a Asm {
+        self.codes
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    // 下一条要执行的指令的位置
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    // 正在执行的指令的位置
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
     pub fn next(&mut self) {
         self.index += 1
     }
 
-    pub fn run(mut asm: Asm) {
-        let mut runtime = Runtime::new(&mut asm);
+    // 一直执行到End或者最外层的Ret, trace在每条指令执行之前调用
+    pub fn execute<T>(&mut self, mut trace: T) -> Result<ExitStatus, RuntimeError>
+    where
+        T: FnMut(&Runtime<'a>, Oper),
+    {
         loop {
-            let oper = runtime.oper();
-            if let Err(e) = runtime.deal_oper(oper) {
-                Runtime::error_print(e);
+            let oper = self.fetch()?;
+            trace(self, oper);
+            if let Some(status) = self.dispatch(oper)? {
+                return Ok(status);
             }
         }
     }
 
-    pub fn run_printing_code(mut asm: Asm, speed: u64, labels: Vec<String>) {
-        let asm_clone = asm.clone();
-        let writer = Box::new(std::io::Cursor::new(vec![0b0; 15]));
-        let mut runtime = Runtime::new_with_writer(&mut asm, writer);
-        loop {
-            let oper = runtime.oper();
-            println!("\x1bcOper:{:?} \n{}", oper, &runtime.machine);
-            asm_clone.display(runtime.index, &labels);
-            println!();
-            if let Err(e) = runtime.deal_oper(oper) {
-                Runtime::error_print(e);
+    // 只执行一条指令, 程序结束时返回Some
+    pub fn step(&mut self) -> Result<Option<ExitStatus>, RuntimeError> {
+        let oper = self.fetch()?;
+        self.dispatch(oper)
+    }
+
+    // 取出下一条指令, 同时检查中断, 指令数和超时
+    fn fetch(&mut self) -> Result<Oper, RuntimeError> {
+        if self.steps == 0 {
+            self.machine.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        }
+        // 中断时还没有取指令, 之后可以从这里继续执行
+        if let Some(interrupt) = &self.machine.interrupt {
+            if interrupt.swap(false, Ordering::Relaxed) {
+                self.current = self.index;
+                return Err(self.error(self.codes.oper(self.index), Interrupted));
             }
-            std::thread::sleep(Duration::from_millis(speed));
         }
+        let oper = self.oper();
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Err(self.error(oper, OutOfFuel));
+            }
+            *fuel -= 1;
+        }
+        self.steps += 1;
+        if self.steps.is_multiple_of(CLOCK_INTERVAL) {
+            self.machine
+                .check_deadline()
+                .map_err(|message| self.error(oper, message))?;
+        }
+        Ok(oper)
     }
 
-    pub fn run_printing(mut asm: Asm, speed: u64) {
-        let mut runtime = Runtime::new(&mut asm);
-        loop {
-            let oper = runtime.oper();
+    fn dispatch(&mut self, oper: Oper) -> Result<Option<ExitStatus>, RuntimeError> {
+        match self.deal_oper(oper) {
+            Ok(State::Running) => Ok(None),
+            Ok(State::Finished) => {
+                self.writer
+                    .flush()
+                    .map_err(|_| self.error(Oper::End, PrintErr))?;
+                Ok(Some(ExitStatus {
+                    stack: std::mem::take(&mut self.machine.stack),
+                }))
+            }
+            Err(message) => match self.catch(message) {
+                Ok(()) => Ok(None),
+                Err(message) => Err(self.error(oper, message)),
+            },
+        }
+    }
+
+    // 交给最里层的Try处理, 没有Try时原样返回错误
+    // 指令数用完, 超时和中断是外面加的限制, 程序自己捕获不到
+    fn catch(&mut self, message: ErrorMessage) -> Result<(), ErrorMessage> {
+        if let OutOfFuel | Timeout | Interrupted = message {
+            return Err(message);
+        }
+        let Some(handler) = self.machine.unwind() else {
+            return Err(message);
+        };
+        // 内置的错误交给处理函数时是一个列表: [错误种类 错误信息]
+        let value = match message {
+            Throw(value) => value,
+            message => Value::List(LinkedList::from([
+                Value::String(message.kind().into()),
+                Value::String(message.text().into()),
+            ])),
+        };
+        self.current = handler.call;
+        self.jmp(handler.ret);
+        let result = self.push(value).and_then(|_| self.local(handler.func));
+        // 处理函数本身没能调用起来时交给外面一层
+        result.or_else(|message| self.catch(message))
+    }
+
+    pub fn run(asm: &'a Asm) -> Result<ExitStatus, RuntimeError> {
+        Runtime::new(asm).execute(|_, _| {})
+    }
+
+    // 统计每种指令和每个函数的执行情况, 出错时也会返回统计
+    pub fn run_profiling(&mut self) -> (Result<ExitStatus, RuntimeError>, Profile) {
+        let mut profiler = Profiler::new();
+        let result = self.execute(|runtime, oper| profiler.step(runtime, oper));
+        (result, profiler.finish(self.codes))
+    }
+
+    // 记录每条指令执行的次数和If选了哪一边
+    pub fn run_coverage(&mut self) -> (Result<ExitStatus, RuntimeError>, Coverage) {
+        let mut coverage = Coverage::new(self.codes);
+        let result = self.execute(|runtime, oper| coverage.step(runtime, oper));
+        (result, coverage)
+    }
+
+    // 每一步都清屏打印字节码, 程序的输出会被丢掉
+    pub fn run_printing_code(
+        &mut self,
+        speed: u64,
+        labels: Vec<String>,
+    ) -> Result<ExitStatus, RuntimeError> {
+        let asm = self.codes;
+        self.writer = Box::new(std::io::Cursor::new(vec![0b0; 15]));
+        self.execute(|runtime, oper| {
+            println!(
+                "\x1bcOper:{:?} {}\n{}",
+                oper,
+                runtime.location(),
+                &runtime.machine
+            );
+            asm.display(runtime.index, &labels);
+            println!();
+            std::thread::sleep(Duration::from_millis(speed));
+        })
+    }
+
+    pub fn run_printing(&mut self, speed: u64) -> Result<ExitStatus, RuntimeError> {
+        self.execute(|runtime, oper| {
             if let Oper::Ret = oper {
             } else {
-                println!("Oper:{:?} \t{:?}", oper, &runtime.machine.stack);
+                println!(
+                    "Oper:{:?} \t{}\t{:?}",
+                    oper,
+                    runtime.location(),
+                    &runtime.machine.stack
+                );
                 std::thread::sleep(Duration::from_millis(speed));
             }
-            if let Err(e) = runtime.deal_oper(oper) {
-                Runtime::error_print(e);
+        })
+    }
+
+    fn error(&self, oper: Oper, message: ErrorMessage) -> RuntimeError {
+        RuntimeError {
+            message,
+            oper,
+            index: self.current,
+            location: self.codes.source_location(self.current),
+            backtrace: self.backtrace(),
+        }
+    }
+
+    // 从当前函数开始沿着调用栈往外走, 每一帧记录它正在执行的位置
+    pub fn backtrace(&self) -> Vec<TraceFrame> {
+        let name = |func: Option<usize>| match func {
+            None => "<main>".to_owned(),
+            Some(ip) => self.codes.function_name(ip),
+        };
+        let mut trace = Vec::new();
+        let mut index = self.current;
+        let mut frames = self.machine.frames.iter().rev();
+        loop {
+            let frame = frames.next();
+            trace.push(TraceFrame {
+                function: name(frame.map(|frame| frame.func)),
+                index,
+                location: self.codes.source_location(index),
+            });
+            match frame {
+                Some(frame) => index = frame.call,
+                None => return trace,
             }
         }
     }
 
-    pub fn error_print(msg: ErrorMessage) {
-        println!("\n\t\x1b[1;31m{}\x1b[0m", msg);
-        std::process::exit(1);
+    // 当前指令对应的源码位置, 没有调试信息时显示字节码位置
+    pub fn location(&self) -> String {
+        self.codes
+            .source_location(self.current)
+            .unwrap_or_else(|| format!("@{}", self.current))
     }
 
-    fn ret(&mut self) {
-        let ip = self.machine.ret_ip();
-        self.jmp(ip);
+    fn ret(&mut self) -> State {
+        match self.machine.ret_ip() {
+            Some(ip) => {
+                self.jmp(ip);
+                State::Running
+            }
+            None => State::Finished,
+        }
     }
 
-    fn pop(&mut self) -> Result<Value<'a>, ErrorMessage> {
+    fn pop(&mut self) -> Result<Value, ErrorMessage> {
         self.machine.pop()
     }
 
-    fn local(&mut self, value: Value<'a>) -> Result<(), ErrorMessage> {
+    fn local(&mut self, value: Value) -> Result<(), ErrorMessage> {
         match value {
-            Value::Function(func) => {
-                self.call(func);
-                Ok(())
-            }
-            Value::Closure(clos) => {
-                self.callosure(clos);
-                Ok(())
-            }
+            Value::Function(func) => self.call(func),
+            Value::Closure(clos) => self.callosure(clos),
             _ => self.machine.push(value),
         }
     }
 
-    fn push(&mut self, value: Value<'a>) -> Result<(), ErrorMessage> {
+    fn push(&mut self, value: Value) -> Result<(), ErrorMessage> {
         self.machine.push(value)
     }
 
-    fn call(&mut self, ip: usize) {
-        self.machine.push_to_local(Value::Function(self.index));
-        self.machine.push_sp();
+    fn call(&mut self, ip: usize) -> Result<(), ErrorMessage> {
+        self.machine.enter(self.index, self.current, ip)?;
         self.jmp(ip);
+        Ok(())
     }
 
-    fn callosure(&mut self, closure: Rc<Closure<'a>>) {
+    fn callosure(&mut self, closure: Rc<Closure>) -> Result<(), ErrorMessage> {
         let ip = closure.ip;
-        self.machine.push_to_local(Value::Function(self.index));
-        self.machine.push_sp();
-        self.machine.push_to_local(Value::Closure(closure));
+        self.machine.enter(self.index, self.current, ip)?;
+        self.machine.push_to_local(Value::Closure(closure))?;
         self.jmp(ip);
+        Ok(())
+    }
+
+    // 在函数末尾调用时复用当前栈帧, 不是函数的值和Local一样放到栈上
+    fn tail(&mut self, value: Value) -> Result<(), ErrorMessage> {
+        if self.machine.frames.is_empty() {
+            return self.local(value);
+        }
+        match value {
+            Value::Function(ip) => {
+                self.machine.reenter(ip);
+                self.jmp(ip);
+                Ok(())
+            }
+            Value::Closure(closure) => {
+                let ip = closure.ip;
+                self.machine.reenter(ip);
+                self.machine.push_to_local(Value::Closure(closure))?;
+                self.jmp(ip);
+                Ok(())
+            }
+            _ => self.machine.push(value),
+        }
     }
 
     pub fn jmp(&mut self, ip: usize) {
@@ -149,6 +431,7 @@ impl<'a> Runtime<'a> {
     }
 
     pub fn oper(&mut self) -> Oper {
+        self.current = self.index;
         let oper = self.codes.oper(self.index);
         self.next();
         oper
@@ -166,9 +449,9 @@ impl<'a> Runtime<'a> {
         offset
     }
 
-    pub fn string(&mut self) -> &'a str {
+    pub fn string(&mut self) -> Rc<str> {
         let offset = self.offset();
-        &self.codes.string_pool[offset]
+        self.strings[offset].clone()
     }
 
     pub fn number(&mut self) -> f64 {
@@ -181,7 +464,7 @@ impl<'a> Runtime<'a> {
         self.codes.function_pool[offset]
     }
 
-    fn deal_oper(&mut self, oper: Oper) -> Result<(), ErrorMessage> {
+    fn deal_oper(&mut self, oper: Oper) -> Result<State, ErrorMessage> {
         use Oper::*;
         use Value::*;
         match oper {
@@ -191,11 +474,11 @@ impl<'a> Runtime<'a> {
             Div => self.binary_f(|x, y| x / y)?,
             DivBy => self.binary_f(|x, y| y / x)?,
             Mul => self.binary_f(|x, y| x * y)?,
-            Mod => self.binary_i(|x, y| x % y)?,
-            ModBy => self.binary_i(|x, y| y % x)?,
-            Xor => self.binary_i(|x, y| x ^ y)?,
-            BitOr => self.binary_i(|x, y| x | y)?,
-            BitAnd => self.binary_i(|x, y| x & y)?,
+            Mod => self.binary_i(remainder)?,
+            ModBy => self.binary_i(|x, y| remainder(y, x))?,
+            Xor => self.binary_i(|x, y| Ok(x ^ y))?,
+            BitOr => self.binary_i(|x, y| Ok(x | y))?,
+            BitAnd => self.binary_i(|x, y| Ok(x & y))?,
             And => self.binary_bool(|x, y| x && y)?,
             Or => self.binary_bool(|x, y| x || y)?,
             Lt => self.binary_cmp(|x, y| x < y)?,
@@ -218,20 +501,20 @@ impl<'a> Runtime<'a> {
             }
 
             Push => {
-                let offset = self.byte();
-                let value = self.machine.local(offset).clone();
+                let offset = self.offset();
+                let value = self.machine.local(offset)?.clone();
                 self.push(value)?
             }
 
             Local => {
-                let offset = self.byte();
-                let value = self.machine.local(offset).clone();
+                let offset = self.offset();
+                let value = self.machine.local(offset)?.clone();
                 self.local(value)?
             }
 
             Pop => {
                 let top = self.pop()?;
-                self.machine.push_to_local(top);
+                self.machine.push_to_local(top)?;
             }
 
             Drop => {
@@ -240,45 +523,57 @@ impl<'a> Runtime<'a> {
 
             Call => {
                 let ip = self.ptr();
-                self.call(ip);
+                self.call(ip)?;
+            }
+
+            Ret => return Ok(self.ret()),
+
+            TailCall => {
+                let ip = self.ptr();
+                self.tail(Function(ip))?;
+            }
+
+            TailLocal => {
+                let offset = self.offset();
+                let value = self.machine.local(offset)?.clone();
+                self.tail(value)?
             }
 
-            Ret => self.ret(),
+            TailCapped => {
+                let index = self.offset();
+                let value = self.machine.captured(index)?;
+                self.tail(value)?;
+            }
 
             Capture => {
                 let (list, index) = self.codes.list(self.index);
-                let capture: Vec<Value> = list
+                let capture = list
                     .iter()
-                    .map(|x| self.machine.local(x.0).clone())
-                    .collect();
+                    .map(|x| self.machine.local(*x).cloned())
+                    .collect::<Result<Vec<Value>, ErrorMessage>>()?;
                 self.jmp(index);
                 self.capture(capture)?
             }
 
             CapCap => {
                 let (list, index) = self.codes.list(self.index);
-                let closure = if let Closure(closure) = self.machine.local(0) {
-                    closure
-                } else {
-                    return Err(NotaClosure);
-                };
-                let capture: Vec<Value> = list
+                let capture = list
                     .iter()
-                    .map(|x| closure.capture[x.0 as usize].clone())
-                    .collect();
+                    .map(|x| self.machine.captured(*x))
+                    .collect::<Result<Vec<Value>, ErrorMessage>>()?;
                 self.jmp(index);
                 self.capture(capture)?
             }
 
             PushCap => {
-                let index = self.byte();
-                let value = self.machine.get_closure()?.capture[index as usize].clone();
+                let index = self.offset();
+                let value = self.machine.captured(index)?;
                 self.push(value)?;
             }
 
             Capped => {
-                let index = self.byte();
-                let value = self.machine.get_closure()?.capture[index as usize].clone();
+                let index = self.offset();
+                let value = self.machine.captured(index)?;
                 self.local(value)?;
             }
 
@@ -289,7 +584,7 @@ impl<'a> Runtime<'a> {
             }
 
             Collect => {
-                self.machine.collect_list();
+                self.machine.collect_list()?;
             }
 
             Insert => {
@@ -313,6 +608,7 @@ impl<'a> Runtime<'a> {
                 let first_list = self.pop()?;
 
                 if let (Value::List(mut first), Value::List(second)) = (first_list, second_list) {
+                    self.machine.check_list_len(first.len() + second.len())?;
                     for item in second.iter() {
                         first.push_back(item.clone());
                     }
@@ -335,7 +631,15 @@ impl<'a> Runtime<'a> {
                 Ok(list)
             })?,
 
-            Input => self.local(Value::Byte(read().expect("读取失败")))?,
+            // 读取本身没法打断, 只能在读完之后检查有没有超时
+            Input => {
+                let byte = self.read()?;
+                self.machine.check_deadline()?;
+                match byte {
+                    Some(byte) => self.local(Value::Byte(byte))?,
+                    None => self.local(Value::Number(EOF))?,
+                }
+            }
 
             Output => {
                 let value = self.pop()?;
@@ -352,7 +656,7 @@ impl<'a> Runtime<'a> {
                 }
             },
 
-            Flush => self.writer.flush().unwrap(),
+            Flush => self.writer.flush().map_err(|_| PrintErr)?,
 
             Oper::Byte => {
                 let byte = self.byte();
@@ -377,10 +681,29 @@ impl<'a> Runtime<'a> {
             True => self.machine.push(Bool(true))?,
 
             False => self.machine.push(Bool(false))?,
-            End => std::process::exit(0),
+            End => return Ok(State::Finished),
+
+            Sys => {
+                let offset = self.offset();
+                let name = &self.codes.syscall_pool[offset];
+                let syscall = self.syscalls.get_mut(name).ok_or(UnknownSyscall)?;
+                syscall(&mut self.machine)?
+            }
+
+            Try => {
+                let body = self.pop()?;
+                let handler = self.pop()?;
+                // 不是函数时和Local一样放到栈上, 没有需要保护的代码
+                if let Function(_) | Closure(_) = body {
+                    self.machine.guard(handler, self.current, self.index);
+                }
+                self.local(body)?
+            }
+
+            Throw => return Err(ErrorMessage::Throw(self.pop()?)),
             _ => unreachable!(),
         };
-        Ok(())
+        Ok(State::Running)
     }
 
     fn unary<T>(&mut self, f: T) -> Result<(), ErrorMessage>
@@ -404,12 +727,12 @@ impl<'a> Runtime<'a> {
 
     fn binary_i<T>(&mut self, f: T) -> Result<(), ErrorMessage>
     where
-        T: Fn(i64, i64) -> i64,
+        T: Fn(i64, i64) -> Result<i64, ErrorMessage>,
     {
         use Value::*;
         let a = self.pop()?;
         let b = self.pop()?;
-        self.local(Number(f(a.into_integer(), b.into_integer()) as f64))
+        self.local(Number(f(a.into_integer(), b.into_integer())? as f64))
     }
 
     fn binary_bool<T>(&mut self, f: T) -> Result<(), ErrorMessage>
@@ -445,16 +768,18 @@ impl<'a> Runtime<'a> {
 
     fn update_list<T>(&mut self, f: T) -> Result<(), ErrorMessage>
     where
-        T: FnOnce(LinkedList<Value<'a>>) -> Result<LinkedList<Value<'a>>, ErrorMessage>,
+        T: FnOnce(LinkedList<Value>) -> Result<LinkedList<Value>, ErrorMessage>,
     {
         if let Value::List(list) = self.pop()? {
-            self.local(Value::List(f(list)?))
+            let list = f(list)?;
+            self.machine.check_list_len(list.len())?;
+            self.local(Value::List(list))
         } else {
             Err(NotaList)
         }
     }
 
-    fn capture(&mut self, capture: Vec<Value<'a>>) -> Result<(), ErrorMessage> {
+    fn capture(&mut self, capture: Vec<Value>) -> Result<(), ErrorMessage> {
         use Value::*;
         match self.pop()? {
             Function(ip) => {
@@ -479,3 +804,12 @@ impl<'a> Runtime<'a> {
         Ok(())
     }
 }
+
+// 对0取余是程序的错误, i64::MIN对-1取余溢出, 结果本来就是0
+fn remainder(x: i64, y: i64) -> Result<i64, ErrorMessage> {
+    if y == 0 {
+        Err(DivByZero)
+    } else {
+        Ok(x.wrapping_rem(y))
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use crate::error::ErrorMessage;
+use crate::machine::Machine;
+use crate::value::Value;
+
+// 系统调用拿到整个Machine, 从栈上取参数, 再把结果放回栈上
+pub type Syscall = Box<dyn FnMut(&mut Machine) -> Result<(), ErrorMessage>>;
+
+// 宿主注册的系统调用, 汇编时用来检查名字, 运行时按名字调用
+#[derive(Default)]
+pub struct Syscalls {
+    table: HashMap<String, Syscall>,
+}
+
+impl Syscalls {
+    pub fn new() -> Self {
+        Syscalls::default()
+    }
+
+    // 解释器自带的系统调用, args默认是空列表, 随机数的种子默认取当前时间
+    pub fn standard() -> Self {
+        let mut syscalls = Syscalls::sandboxed();
+        crate::file::register(&mut syscalls);
+        syscalls.register("env", |machine| {
+            // 没有设置的环境变量是False
+            let value = match machine.pop()? {
+                Value::String(name) => std::env::var(&*name)
+                    .map_or(Value::Bool(false), |value| Value::String(value.into())),
+                value => {
+                    let message = format!("环境变量名必须是字符串, 而不是{:?}", value);
+                    return Err(ErrorMessage::Syscall(message));
+                }
+            };
+            machine.push(value)
+        });
+        syscalls
+    }
+
+    // 去掉了读写文件和环境变量的系统调用, 用来运行不信任的程序
+    pub fn sandboxed() -> Self {
+        let mut syscalls = Syscalls::new();
+        crate::clock::register(&mut syscalls);
+        syscalls
+            .with_args(Vec::new())
+            .with_seed(crate::random::seed())
+    }
+
+    // 固定随机数的种子, 每次运行得到同样的结果
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        crate::random::register(&mut self, seed);
+        self
+    }
+
+    // 程序的命令行参数, 每次调用args都得到一个新的字符串列表
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        let args: Vec<Value> = args
+            .into_iter()
+            .map(|arg| Value::String(arg.into()))
+            .collect();
+        self.register("args", move |machine| {
+            machine.check_list_len(args.len())?;
+            machine.push(Value::List(args.iter().cloned().collect()))
+        });
+        self
+    }
+
+    // 同名的系统调用会被替换
+    pub fn register<F>(&mut self, name: &str, syscall: F)
+    where
+        F: FnMut(&mut Machine) -> Result<(), ErrorMessage> + 'static,
+    {
+        self.table.insert(name.to_owned(), Box::new(syscall));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.table.contains_key(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.table.keys().map(String::as_str)
+    }
+
+    pub(crate) fn get_mut(&mut self, name: &str) -> Option<&mut Syscall> {
+        self.table.get_mut(name)
+    }
+}
@@ -0,0 +1,653 @@
+use crate::assemble::Asm;
+use crate::command::{Cmd, Oper};
+use crate::error::{ErrorMessage, LoadError, VerifyError};
+use crate::machine::Limits;
+use crate::parser::{AsmBuilder, AsmCmd, Number};
+use crate::program::Program;
+use crate::runtime::Runtime;
+use crate::syscall::Syscalls;
+use crate::value::Value;
+use crate::writer::checksum;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn assemble(source: &str) -> Asm {
+    Asm::from(AsmBuilder::from_str(source).unwrap())
+}
+
+fn stack(asm: &Asm) -> Vec<Value> {
+    Runtime::new_with_writer(asm, Box::new(std::io::sink()))
+        .execute(|_, _| {})
+        .unwrap()
+        .stack
+}
+
+// 运行结束时栈上的数字
+fn run(asm: &Asm) -> Vec<f64> {
+    stack(asm)
+        .iter()
+        .map(|value| match value {
+            Value::Number(number) => *number,
+            value => panic!("栈上不是数字: {:?}", value),
+        })
+        .collect()
+}
+
+const SQUARES: &str = "
+Add square 3 square 1.5
+Print \"done\"
+End
+square {
+  Let
+  Mul $0 $0
+}
+";
+
+// 改完字节后重新算校验和, 让错误落到校验和后面的检查上
+fn resign(bytes: &mut [u8]) {
+    let body = bytes.len() - 4;
+    let sum = checksum(&bytes[..body]);
+    bytes[body..].copy_from_slice(&sum.to_le_bytes());
+}
+
+#[test]
+fn bytecode_round_trip() {
+    let asm = assemble(SQUARES);
+    let bytes = asm.to_bytes();
+    let loaded = Asm::from_bytes(&bytes).unwrap();
+    assert_eq!(loaded.to_bytes(), bytes);
+    assert_eq!(loaded.string_pool, asm.string_pool);
+    assert_eq!(loaded.number_pool, asm.number_pool);
+    assert_eq!(loaded.function_pool, asm.function_pool);
+    assert_eq!(run(&loaded), vec![11.25]);
+}
+
+#[test]
+fn bytecode_truncated() {
+    let bytes = assemble(SQUARES).to_bytes();
+    assert!(matches!(
+        Asm::from_bytes(&bytes[..6]),
+        Err(LoadError::Truncated)
+    ));
+    for length in 0..bytes.len() {
+        assert!(Asm::from_bytes(&bytes[..length]).is_err());
+    }
+}
+
+#[test]
+fn bytecode_bad_checksum() {
+    let mut bytes = assemble(SQUARES).to_bytes();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    assert!(matches!(Asm::from_bytes(&bytes), Err(LoadError::Checksum)));
+    bytes[middle] ^= 0xff;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    assert!(matches!(Asm::from_bytes(&bytes), Err(LoadError::Checksum)));
+}
+
+#[test]
+fn bytecode_bad_version() {
+    let mut bytes = assemble(SQUARES).to_bytes();
+    for version in [1, 99] {
+        bytes[4..6].copy_from_slice(&u16::to_le_bytes(version));
+        resign(&mut bytes);
+        assert!(matches!(
+            Asm::from_bytes(&bytes),
+            Err(LoadError::Version(found)) if found == version
+        ));
+    }
+}
+
+// 校验和没问题, 但是指令引用了不存在的常量
+#[test]
+fn bytecode_is_verified_on_load() {
+    let cmds = [Oper::Num as u8, 3, Oper::Print as u8, 0xff]
+        .iter()
+        .map(|byte| Cmd(*byte))
+        .collect();
+    let bytes = Asm::new(cmds, Vec::new(), Vec::new(), Vec::new()).to_bytes();
+    let Err(LoadError::Invalid(diagnostics)) = Asm::from_bytes(&bytes) else {
+        panic!("没有通过检查的字节码被加载了");
+    };
+    assert!(matches!(diagnostics[0].error, VerifyError::BadNumber(3)));
+}
+
+// 用到了各种操作数: 局部变量, 捕获, 字节, 字符串转义, 系统调用, 尾调用
+const FEATURES: &str = "
+Print fact 5
+Print Length List (init)
+Print Try (oops) (handle)
+Print Concat \"a\\\"b\\n\" \"\"
+Drop Sys time.now
+Drop Add 'a' '\\x7f'
+Drop Mul 0.5 Add 1e300 -2
+End
+
+fact {
+  Let
+  do If Le $0 1 (one) $[0] (fact_if)
+}
+do { $0 Let }
+one { 1 }
+fact_if {
+  Mul #0 fact Sub #0 1
+}
+
+init:
+  1 2 3
+  adder 2
+Ret
+
+adder {
+  Let
+  Let
+  do $[0 1] (add_both)
+}
+add_both {
+  do #[1 0] (add_again) (#0) ($0)
+}
+add_again {
+  Add #0 #1
+}
+
+oops { Throw \"oops\" }
+handle {
+  Let
+  countdown 3
+  $0
+}
+countdown {
+  Let
+  If Eq $0 0 0 countdown Sub $0 1
+}
+";
+
+#[test]
+fn disassemble_round_trip() {
+    let (asm, labels) = Asm::with_labels(AsmBuilder::from_str(FEATURES).unwrap());
+    let source = asm.disassemble(&labels).unwrap();
+    let (again, _) = Asm::with_labels(AsmBuilder::from_str(&source).unwrap());
+    let bytes = |asm: &Asm| asm.cmds.iter().map(|cmd| cmd.0).collect::<Vec<u8>>();
+    assert_eq!(bytes(&again), bytes(&asm), "{}", source);
+    assert_eq!(again.string_pool, asm.string_pool);
+    assert_eq!(again.number_pool, asm.number_pool);
+    assert_eq!(again.function_pool, asm.function_pool);
+    assert_eq!(again.syscall_pool, asm.syscall_pool);
+}
+
+// 手写的字节码, 只有一个数字常量1
+fn verify_bytes(bytes: &[u8], functions: Vec<usize>) -> Vec<(usize, VerifyError)> {
+    let cmds = bytes.iter().map(|byte| Cmd(*byte)).collect();
+    let asm = Asm::new(cmds, Vec::new(), vec![Number(1.0)], functions);
+    match asm.verify() {
+        Ok(()) => Vec::new(),
+        Err(diagnostics) => diagnostics
+            .into_iter()
+            .map(|x| (x.index, x.error))
+            .collect(),
+    }
+}
+
+#[test]
+fn verify_accepts_assembled_code() {
+    assert!(assemble(SQUARES).verify().is_ok());
+    assert!(assemble(FEATURES).verify().is_ok());
+}
+
+#[test]
+fn verify_errors() {
+    use Oper::*;
+    use VerifyError::*;
+    let end = End as u8;
+    let errors = verify_bytes(&[Num as u8, 0, 0xff, end], vec![]);
+    assert!(matches!(errors[..], [(2, UnknownOper(0xff))]));
+    let errors = verify_bytes(&[end, Num as u8], vec![]);
+    assert!(matches!(errors[..], [(1, Truncated)]));
+    let errors = verify_bytes(&[Capture as u8, 2, 0x80], vec![]);
+    assert!(matches!(errors[..], [(0, Truncated)]));
+    let mut overflow = vec![Num as u8];
+    overflow.extend([0xff; 10]);
+    overflow.extend([0x7f, end]);
+    let errors = verify_bytes(&overflow, vec![]);
+    assert!(matches!(errors[..], [(0, Overflow)]));
+    let errors = verify_bytes(&[Str as u8, 0, end], vec![]);
+    assert!(matches!(errors[..], [(0, BadString(0))]));
+    let errors = verify_bytes(&[Num as u8, 3, end], vec![]);
+    assert!(matches!(errors[..], [(0, BadNumber(3))]));
+    let errors = verify_bytes(&[Call as u8, 1, Func as u8, 0, end], vec![4]);
+    assert!(matches!(errors[..], [(0, BadFunction(1))]));
+    let errors = verify_bytes(&[Sys as u8, 0, end], vec![]);
+    assert!(matches!(errors[..], [(0, BadSyscall(0))]));
+    let errors = verify_bytes(&[Num as u8, 0, end], vec![2, 1]);
+    assert!(matches!(errors[..], [(1, BadTarget(1))]));
+    let errors = verify_bytes(&[end, Num as u8, 0], vec![]);
+    assert!(matches!(errors[..], [(1, FallsOffEnd)]));
+    let errors = verify_bytes(&[], vec![]);
+    assert!(matches!(errors[..], [(0, FallsOffEnd)]));
+}
+
+// 捕获和局部变量的下标在运行时检查, 出错时可以被Try接住
+#[test]
+fn bad_indices_are_runtime_errors() {
+    let capture = assemble("Let 7\ndo $[0] (two)\nEnd\ndo { $0 Let }\ntwo { #1 }\n");
+    let err = Runtime::run(&capture).unwrap_err();
+    assert!(matches!(err.message, ErrorMessage::NoSuchCapture));
+    let local = assemble("$3\nEnd\n");
+    let err = Runtime::run(&local).unwrap_err();
+    assert!(matches!(err.message, ErrorMessage::NoSuchLocal));
+    let caught = assemble(
+        "Try (bad) (handle)\nEnd\ndo { $0 Let }\ntwo { #1 }\nhandle { 5 Drop }\nbad {\n  Let 7\n  do $[0] (two)\n}\n",
+    );
+    assert_eq!(run(&caught), vec![5.0]);
+}
+
+// NaN和NaN也算相同, 0和-0不同
+fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => {
+            x.to_bits() == y.to_bits() || x.is_nan() && y.is_nan()
+        }
+        (Value::Bool(p), Value::Bool(q)) => p == q,
+        _ => false,
+    }
+}
+
+fn has_oper(builder: &AsmBuilder, oper: Oper) -> bool {
+    builder
+        .cmds
+        .iter()
+        .any(|(cmd, _)| matches!(cmd, AsmCmd::Command(found) if *found as u8 == oper as u8))
+}
+
+#[test]
+fn folding_matches_runtime() {
+    use Oper::*;
+    let opers = [
+        Add, Sub, SubBy, Div, DivBy, Mul, Mod, ModBy, Xor, BitOr, BitAnd, And, Or, Lt, Gt, Eq, Le,
+        Ge,
+    ];
+    // Div 0 0 是NaN, 0.5取整之后也是0
+    let operands = [
+        "0", "0.5", "7", "-7", "2.5", "1e999", "-1e999", "Div 0 0", "True",
+    ];
+    for oper in opers {
+        for a in operands {
+            for b in operands {
+                let source = format!("{:?} {} {}\nEnd\n", oper, a, b);
+                let builder = AsmBuilder::from_str(&source).unwrap();
+                let mut optimized = builder.clone();
+                optimized.optimize();
+                let divisor = match oper {
+                    Mod => b,
+                    ModBy => a,
+                    _ => "",
+                };
+                if matches!(divisor, "0" | "0.5" | "Div 0 0") {
+                    // 运行时对0取余会报错, 优化器不能把它折叠掉
+                    assert!(has_oper(&optimized, oper), "{}", source);
+                    let err = Runtime::run(&Asm::from(optimized)).unwrap_err();
+                    assert!(matches!(err.message, ErrorMessage::DivByZero));
+                    continue;
+                }
+                let expected = stack(&Asm::from(builder));
+                let folded = stack(&Asm::from(optimized));
+                assert_eq!(expected.len(), 1, "{}", source);
+                assert_eq!(folded.len(), 1, "{}", source);
+                assert!(
+                    same(&expected[0], &folded[0]),
+                    "{}: {:?} != {:?}",
+                    source,
+                    expected[0],
+                    folded[0]
+                );
+            }
+        }
+    }
+
+    let mut builder = AsmBuilder::from_str("Mod -7 2.5\nEnd\n").unwrap();
+    builder.optimize();
+    assert!(!has_oper(&builder, Mod));
+}
+
+// 尾调用不占新的栈帧, 一百万次循环也不会超过很小的调用深度
+#[test]
+fn tail_calls_stay_shallow() {
+    let asm = assemble(
+        "
+count 0 1000000
+End
+
+count {
+  Let
+  Let
+  do If Eq $1 0 $[0] (done) $[0 1] (next)
+}
+do { $0 Let }
+done { #0 }
+next {
+  count Add #0 1 Sub #1 1
+}
+",
+    );
+    let limits = Limits {
+        max_depth: 8,
+        max_locals: 32,
+        ..Limits::default()
+    };
+    let status = Runtime::new(&asm).with_limits(limits).execute(|_, _| {});
+    let stack = status.unwrap().stack;
+    assert!(matches!(stack[..], [Value::Number(count)] if count == 1e6));
+}
+
+// 内置的错误交给处理函数时是 [错误种类 错误信息]
+#[test]
+fn builtin_errors_reach_handler() {
+    let asm = assemble("Try (bad) (handle)\nEnd\nbad { Mod 5 0 }\nhandle { Let\n  $0\n}\n");
+    let stack = stack(&asm);
+    let Some(Value::List(error)) = stack.first() else {
+        panic!("处理函数没有收到列表: {:?}", stack);
+    };
+    let error: Vec<String> = error
+        .iter()
+        .map(|value| match value {
+            Value::String(str) => str.to_string(),
+            value => panic!("不是字符串: {:?}", value),
+        })
+        .collect();
+    assert_eq!(error, ["div_by_zero", "不可以对0取余!"]);
+
+    // i64::MIN对-1取余不会溢出
+    assert_eq!(run(&assemble("Mod -1e300 -1\nEnd\n")), vec![0.0]);
+}
+
+// 输出失败时返回错误, 而不是让整个进程崩溃
+struct BrokenPipe;
+
+impl std::io::Write for BrokenPipe {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+#[test]
+fn output_errors_are_runtime_errors() {
+    for source in ["Flush\nEnd\n", "Print 1\nEnd\n"] {
+        let asm = assemble(source);
+        let err = Runtime::new_with_writer(&asm, Box::new(BrokenPipe))
+            .execute(|_, _| {})
+            .unwrap_err();
+        assert!(matches!(err.message, ErrorMessage::PrintErr), "{}", source);
+    }
+}
+
+#[test]
+fn sandbox_leaves_out_files_and_env() {
+    let standard = Syscalls::standard();
+    let sandboxed = Syscalls::sandboxed();
+    for name in [
+        "file.open",
+        "file.create",
+        "file.append",
+        "file.write",
+        "env",
+    ] {
+        assert!(standard.contains(name));
+        assert!(!sandboxed.contains(name), "{}", name);
+    }
+    assert!(sandboxed.contains("time.now"));
+    assert!(sandboxed.contains("args"));
+    let builder = AsmBuilder::from_str("Sys file.create \"out.txt\"\nEnd\n").unwrap();
+    assert!(builder.check_syscalls(&sandboxed).is_err());
+}
+
+// 语法允许的写法都不能让from_str崩溃
+#[test]
+fn literals_and_escapes() {
+    let bytes = |source: &str| match &stack(&assemble(source))[..] {
+        [Value::Byte(byte)] => *byte,
+        stack => panic!("{}: {:?}", source, stack),
+    };
+    assert_eq!(bytes("41H\nEnd\n"), 0x41);
+    assert_eq!(bytes("0ffH\nEnd\n"), 0xff);
+    assert_eq!(bytes("'\\t'\nEnd\n"), b'\t');
+    assert_eq!(bytes("'\\\\'\nEnd\n"), b'\\');
+    assert_eq!(bytes("'\\''\nEnd\n"), b'\'');
+    assert_eq!(bytes("'\\x7f'\nEnd\n"), 0x7f);
+
+    let asm = assemble("\"a\\/b\\bc\\fd\\\"\"\nEnd\n");
+    assert_eq!(asm.string_pool, ["a/b\u{8}c\u{c}d\""]);
+
+    for source in ["''\n", "'ab'\n", "'你'\n"] {
+        assert!(AsmBuilder::from_str(source).is_err(), "{}", source);
+    }
+}
+
+// 删掉没用的函数之后, 函数池里剩下的标签
+fn live_labels(source: &str) -> (Asm, Vec<String>) {
+    Asm::from_builder(AsmBuilder::from_str(source).unwrap())
+}
+
+#[test]
+fn dead_functions_referenced_only_from_dead_code() {
+    let (asm, labels) = live_labels(
+        "
+Print 1
+End
+dead {
+  (ghost)
+}
+ghost { 2 }
+",
+    );
+    assert!(labels.is_empty(), "{:?}", labels);
+    assert_eq!(asm.number_pool, [Number(1.0)]);
+}
+
+#[test]
+fn dead_functions_adjacent_labels() {
+    let (asm, labels) = live_labels(
+        "
+a 1
+End
+a:
+b:
+  Add 1
+Ret
+c:
+d:
+  Sub 1
+Ret
+",
+    );
+    assert_eq!(labels, ["a", "b"]);
+    assert_eq!(run(&asm), vec![2.0]);
+}
+
+#[test]
+fn dead_functions_fall_through_into_label() {
+    let (asm, labels) = live_labels(
+        "
+first 1
+End
+first:
+  Add 1
+second:
+  Mul 2
+Ret
+third:
+  Sub 1
+Ret
+",
+    );
+    assert_eq!(labels, ["first", "second"]);
+    assert_eq!(run(&asm), vec![4.0]);
+}
+
+#[test]
+fn dead_functions_compact_function_pool() {
+    let (asm, labels) = live_labels(
+        "
+Add one two
+End
+unused_a { 10 }
+one { 1 }
+unused_b { 20 }
+two { 2 }
+unused_c { 30 }
+",
+    );
+    // 函数按第一次引用的顺序编号, 从右往左执行所以two在前
+    assert_eq!(labels, ["two", "one"]);
+    assert_eq!(asm.function_pool.len(), 2);
+    assert!(asm.function_pool.iter().all(|ip| *ip < asm.cmds.len()));
+    assert!(asm.verify().is_ok());
+    assert_eq!(run(&asm), vec![3.0]);
+    assert_eq!(asm.number_pool.len(), 2);
+}
+
+// 每个测试用自己的临时目录, 测试是并行跑的
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sognasm-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn load(path: &Path, search: &[PathBuf]) -> Result<Program, String> {
+    let content = fs::read_to_string(path).unwrap();
+    Program::load(path, content, search).map_err(|err| err.to_string())
+}
+
+fn run_program(program: &Program) -> Vec<f64> {
+    run(&Asm::from(AsmBuilder::from_program(program).unwrap()))
+}
+
+#[test]
+fn import_cycles() {
+    let dir = scratch("cycle");
+    let main = write(&dir, "main.sasm", "Import \"left.sasm\"\nEnd\n");
+    write(&dir, "left.sasm", "Import \"right.sasm\"\n");
+    write(&dir, "right.sasm", "Import \"left.sasm\"\n");
+    let err = load(&main, &[]).err().unwrap();
+    assert!(err.contains("循环导入 left.sasm"), "{}", err);
+
+    let main = write(&dir, "selfish.sasm", "Import \"selfish.sasm\"\nEnd\n");
+    let err = load(&main, &[]).err().unwrap();
+    assert!(err.contains("循环导入"), "{}", err);
+
+    // 两个文件导入同一个文件不是循环, 它只加载一次
+    let main = write(
+        &dir,
+        "diamond.sasm",
+        "Import \"left_d.sasm\"\nImport \"right_d.sasm\"\nAdd left_d.one right_d.one\nEnd\n",
+    );
+    write(
+        &dir,
+        "left_d.sasm",
+        "Import \"shared.sasm\"\none { shared.one }\n",
+    );
+    write(
+        &dir,
+        "right_d.sasm",
+        "Import \"shared.sasm\"\none { shared.one }\n",
+    );
+    write(&dir, "shared.sasm", "one { 1 }\n");
+    let program = load(&main, &[]).unwrap();
+    assert_eq!(program.sources.len(), 4);
+    assert_eq!(run_program(&program), vec![2.0]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn import_namespace_collisions() {
+    let dir = scratch("collision");
+    let main = write(
+        &dir,
+        "main.sasm",
+        "Import \"x/util.sasm\"\nImport \"y/util.sasm\"\nEnd\n",
+    );
+    write(&dir, "x/util.sasm", "one { 1 }\n");
+    write(&dir, "y/util.sasm", "two { 2 }\n");
+    let err = load(&main, &[]).err().unwrap();
+    assert!(err.contains("命名空间 util"), "{}", err);
+
+    let main = write(&dir, "dashed.sasm", "Import \"not-a-name.sasm\"\nEnd\n");
+    write(&dir, "not-a-name.sasm", "one { 1 }\n");
+    let err = load(&main, &[]).err().unwrap();
+    assert!(err.contains("不能作为命名空间"), "{}", err);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+// 先找导入它的文件所在的目录, 再找-I, 最后找SOGNASM_PATH
+#[test]
+fn import_search_order() {
+    let dir = scratch("search");
+    let main = write(
+        &dir,
+        "src/main.sasm",
+        "Import \"ordered.sasm\"\nordered.which\nEnd\n",
+    );
+    let local = write(&dir, "src/ordered.sasm", "which { 1 }\n");
+    let include = write(&dir, "include/ordered.sasm", "which { 2 }\n");
+    let from_env = write(&dir, "env/ordered.sasm", "which { 3 }\n");
+    std::env::set_var("SOGNASM_PATH", dir.join("env"));
+    let search = [dir.join("include")];
+
+    assert_eq!(run_program(&load(&main, &search).unwrap()), vec![1.0]);
+    fs::remove_file(local).unwrap();
+    assert_eq!(run_program(&load(&main, &search).unwrap()), vec![2.0]);
+    fs::remove_file(include).unwrap();
+    assert_eq!(run_program(&load(&main, &search).unwrap()), vec![3.0]);
+    fs::remove_file(from_env).unwrap();
+    let err = load(&main, &search).err().unwrap();
+    assert!(err.contains("找不到导入的文件 ordered.sasm"), "{}", err);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+// 导入的文件里的标签和没有限定的引用都加上文件名, 已经限定的引用不变
+#[test]
+fn import_qualifies_names() {
+    let dir = scratch("qualify");
+    let main = write(
+        &dir,
+        "main.sasm",
+        "Import \"geometry.sasm\"\nAdd twice geometry.area 3\nEnd\ntwice { 100 }\n",
+    );
+    write(
+        &dir,
+        "geometry.sasm",
+        "Import \"unit.sasm\"\narea {\n  Let\n  Mul unit.one twice $0\n}\ntwice {\n  Let\n  Mul $0 $0\n}\n",
+    );
+    write(&dir, "unit.sasm", "one { 1 }\n");
+    let program = load(&main, &[]).unwrap();
+    let namespaces: Vec<Option<&str>> = program
+        .sources
+        .iter()
+        .map(|source| source.namespace.as_deref())
+        .collect();
+    assert_eq!(namespaces, [None, Some("geometry"), Some("unit")]);
+
+    let builder = AsmBuilder::from_program(&program).unwrap();
+    let (asm, labels) = Asm::from_builder(builder);
+    let mut labels = labels;
+    labels.sort();
+    assert_eq!(
+        labels,
+        ["geometry.area", "geometry.twice", "twice", "unit.one"]
+    );
+    // geometry.area里的twice是geometry.twice, 不是入口文件的twice
+    assert_eq!(run(&asm), vec![109.0]);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,55 +1,57 @@
-pub fn unescape(s: &str) -> String {
+// 和语法里的identifier一样, 只有小写字母和下划线
+pub fn is_identifier(str: &str) -> bool {
+    !str.is_empty() && str.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+}
+
+// 语法允许的转义之外的写法返回None
+pub fn unescape(s: &str) -> Option<String> {
     let mut result = String::with_capacity(s.len());
     let mut chars = s.chars();
 
     while let Some(c) = chars.next() {
         if c == '\\' {
-            match chars.next() {
-                Some('n') => result.push('\n'),
-                Some('r') => result.push('\r'),
-                Some('t') => result.push('\t'),
-                Some('\\') => result.push('\\'),
-                Some('\'') => result.push('\''),
-                Some('\"') => result.push('\"'),
-                Some('0') => result.push('\0'),
-                Some('x') => {
-                    // Handle \xHH hex escapes
-                    let hex1 = chars.next().expect("奇怪的转义");
-                    let hex2 = chars.next().expect("奇怪的转义");
-                    let hex_str = format!("{}{}", hex1, hex2);
-                    let byte = u8::from_str_radix(&hex_str, 16).expect("奇怪的转义，不是十六进制");
-                    result.push(byte as char);
-                }
-                _ => panic!("不认识的转义"),
+            match chars.next()? {
+                'x' => result.push(hex(&mut chars)? as char),
+                c => result.push(escaped(c)? as char),
             }
         } else {
             result.push(c);
         }
     }
-    result
+    Some(result)
 }
 
-pub fn uneccape(s: &str) -> u8 {
+// 字符只能是一个字节, 空的''或者多个字符返回None
+pub fn uneccape(s: &str) -> Option<u8> {
     let mut chars = s.chars();
-    let first = chars.next().unwrap();
-    if first == '\\' {
-        match chars.next() {
-            Some('n') => b'\n',
-            Some('r') => b'\n',
-            Some('t') => b'\n',
-            Some('\\') => b'\n',
-            Some('\'') => b'\n',
-            Some('\"') => b'\n',
-            Some('0') => b'\n',
-            Some('x') => {
-                let hex1 = chars.next().expect("奇怪的转义");
-                let hex2 = chars.next().expect("奇怪的转义");
-                let hex_str = format!("{}{}", hex1, hex2);
-                u8::from_str_radix(&hex_str, 16).expect("奇怪的转义，不是十六进制")
-            }
-            _ => panic!("不认识的转义"),
-        }
-    } else {
-        first as u8
+    let byte = match chars.next()? {
+        '\\' => match chars.next()? {
+            'x' => hex(&mut chars)?,
+            c => escaped(c)?,
+        },
+        c => u8::try_from(c).ok().filter(u8::is_ascii)?,
+    };
+    chars.next().is_none().then_some(byte)
+}
+
+fn escaped(c: char) -> Option<u8> {
+    match c {
+        'n' => Some(b'\n'),
+        'r' => Some(b'\r'),
+        't' => Some(b'\t'),
+        'b' => Some(0x08),
+        'f' => Some(0x0c),
+        '0' => Some(b'\0'),
+        '\\' | '/' | '\'' | '"' => Some(c as u8),
+        _ => None,
+    }
+}
+
+// \x后面的两位十六进制数
+fn hex(chars: &mut std::str::Chars) -> Option<u8> {
+    let digits: String = chars.take(2).collect();
+    if digits.len() != 2 {
+        return None;
     }
+    u8::from_str_radix(&digits, 16).ok()
 }
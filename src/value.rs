@@ -3,29 +3,33 @@ use std::{collections::LinkedList, rc::Rc};
 
 use colored::Colorize;
 
-pub struct Closure<'a> {
-    pub capture: Vec<Value<'a>>,
+use crate::file::Handle;
+
+pub struct Closure {
+    pub capture: Vec<Value>,
     pub ip: usize,
 }
 
 #[derive(Clone)]
-pub enum Value<'a> {
+pub enum Value {
     Number(f64),
     Function(usize),
-    Closure(Rc<Closure<'a>>),
-    List(LinkedList<Value<'a>>),
-    String(&'a str),
+    Closure(Rc<Closure>),
+    List(LinkedList<Value>),
+    String(Rc<str>),
     Byte(u8),
     Bool(bool),
+    // 打开的文件, 只能交给file.*系统调用
+    Handle(Rc<Handle>),
 }
 
-impl std::fmt::Debug for Value<'_> {
+impl std::fmt::Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.color_str())
     }
 }
 
-impl Value<'_> {
+impl Value {
     fn color_str(&self) -> String {
         use Value::*;
         match self {
@@ -34,12 +38,17 @@ impl Value<'_> {
             Closure(rc) => format!("C{}", rc.ip).yellow().to_string(),
             List(list) => format!("{:?}", list).cyan().to_string(),
             String(str) => {
-                let str = if str.len() > 5 { &str[..5] } else { str };
+                // 按字符截断, 中文不能从中间切开
+                let str = match str.char_indices().nth(5) {
+                    Some((end, _)) => &str[..end],
+                    None => str,
+                };
                 format!("\x1b[32m\"{str}\"\x1b[0m")
             }
 
             Byte(byte) => byte.to_string().bright_blue().to_string(),
             Bool(bool) => bool.to_string().red().to_string(),
+            Handle(handle) => format!("<{}>", handle.path).magenta().to_string(),
         }
     }
 
@@ -53,13 +62,14 @@ impl Value<'_> {
             String(_) => b's',
             Byte(_) => b'x',
             Bool(_) => b'b',
+            Handle(_) => b'h',
         }
     }
     pub fn into_integer(self) -> i64 {
         use Value::*;
         match self {
             Number(number) => number as i64,
-            Function(_) | Closure(_) => 0,
+            Function(_) | Closure(_) | Handle(_) => 0,
             List(list) => list.len() as i64,
             String(str) => str.parse::<f64>().unwrap_or(f64::NAN) as i64,
             Byte(byte) => byte as i64,
@@ -78,7 +88,7 @@ impl Value<'_> {
         match self {
             Number(number) => number == 0.0,
             Function(_) => false,
-            Closure(_) => false,
+            Closure(_) | Handle(_) => false,
             List(list) => list.is_empty(),
             String(str) => str.is_empty(),
             Byte(byte) => byte == 0,
@@ -90,7 +100,7 @@ impl Value<'_> {
         use Value::*;
         match self {
             Number(number) => number,
-            Function(_) | Closure(_) => f64::NAN,
+            Function(_) | Closure(_) | Handle(_) => f64::NAN,
             List(list) => list.len() as f64,
             String(str) => str.parse().unwrap_or(f64::NAN),
             Byte(byte) => byte as f64,
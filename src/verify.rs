@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use crate::assemble::{Asm, Operand};
+use crate::command::Oper;
+use crate::error::{DecodeError, VerifyError};
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub index: usize,
+    pub error: VerifyError,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "位置{}: {}", self.index, self.error)
+    }
+}
+
+struct Verifier<'a> {
+    asm: &'a Asm,
+    diagnostics: Vec<Diagnostic>,
+    // 每条指令的起始位置和解码结果
+    instructions: Vec<(usize, Oper, Operand)>,
+}
+
+impl<'a> Verifier<'a> {
+    fn report(&mut self, index: usize, error: VerifyError) {
+        self.diagnostics.push(Diagnostic { index, error });
+    }
+
+    fn decode_all(&mut self) {
+        let mut index = 0;
+        while index < self.asm.cmds.len() {
+            match self.asm.decode(index) {
+                Ok((oper, operand, next)) => {
+                    self.instructions.push((index, oper, operand));
+                    index = next;
+                }
+                // 解码失败后无法确定下一条指令的位置, 只能停下
+                Err(DecodeError::UnknownOper(index, byte)) => {
+                    return self.report(index, VerifyError::UnknownOper(byte))
+                }
+                Err(DecodeError::Overflow(_)) => return self.report(index, VerifyError::Overflow),
+                Err(_) => return self.report(index, VerifyError::Truncated),
+            }
+        }
+    }
+
+    fn check_operands(&mut self) {
+        use Oper::*;
+        let asm = self.asm;
+        let mut errors = Vec::new();
+        for (index, oper, operand) in &self.instructions {
+            let (pool, error): (usize, fn(usize) -> VerifyError) = match oper {
+                Str => (asm.string_pool.len(), VerifyError::BadString),
+                Num => (asm.number_pool.len(), VerifyError::BadNumber),
+                Call | Func | TailCall => (asm.function_pool.len(), VerifyError::BadFunction),
+                Sys => (asm.syscall_pool.len(), VerifyError::BadSyscall),
+                _ => continue,
+            };
+            if let Operand::Offset(offset) = operand {
+                if *offset >= pool {
+                    errors.push((*index, error(*offset)));
+                }
+            }
+        }
+        for (index, error) in errors {
+            self.report(index, error);
+        }
+    }
+
+    fn check_targets(&mut self) {
+        let starts: HashSet<usize> = self.instructions.iter().map(|x| x.0).collect();
+        for (func, ip) in self.asm.function_pool.iter().enumerate() {
+            if !starts.contains(ip) {
+                self.report(*ip, VerifyError::BadTarget(func));
+            }
+        }
+    }
+
+    fn check_end(&mut self) {
+        match self.instructions.last() {
+            Some((_, Oper::End | Oper::Ret, _)) => {}
+            Some((index, _, _)) => self.report(*index, VerifyError::FallsOffEnd),
+            None => self.report(0, VerifyError::FallsOffEnd),
+        }
+    }
+}
+
+impl Asm {
+    // 在运行之前检查字节码, 避免运行到一半才因为下标越界崩溃
+    pub fn verify(&self) -> Result<(), Vec<Diagnostic>> {
+        let mut verifier = Verifier {
+            asm: self,
+            diagnostics: Vec::new(),
+            instructions: Vec::new(),
+        };
+        verifier.decode_all();
+        verifier.check_operands();
+        // 没能完整解码的话, 入口和结尾的检查都没有意义
+        if verifier.diagnostics.is_empty() {
+            verifier.check_targets();
+            verifier.check_end();
+        }
+
+        if verifier.diagnostics.is_empty() {
+            Ok(())
+        } else {
+            verifier.diagnostics.sort_by_key(|x| x.index);
+            Err(verifier.diagnostics)
+        }
+    }
+}
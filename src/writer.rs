@@ -0,0 +1,170 @@
+use crate::assemble::Asm;
+use crate::debug::DebugInfo;
+
+// 字节码文件结构(所有整数均为小端序):
+//   magic(4) | version(u16) | 段数量(u16)
+//   段表: [种类(u8) | 偏移(u32) | 长度(u32)] * 段数量
+//   各段数据
+//   checksum(u32), 对之前所有字节做 FNV-1a
+pub const MAGIC: &[u8; 4] = b"SGBC";
+// 版本2: 操作数改成LEB128变长编码
+// 版本3: 增加尾调用指令, 版本2的字节码仍然可以加载
+// 版本4: 增加Sys指令和系统调用名段
+// 版本5: 增加Try和Throw指令
+pub const VERSION: u16 = 5;
+pub const MIN_VERSION: u16 = 2;
+
+pub const HEADER_SIZE: usize = 8;
+pub const ENTRY_SIZE: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Section {
+    Code = 1,
+    Strings,
+    Numbers,
+    Functions,
+    Debug,
+    Syscalls,
+}
+
+impl Section {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        use Section::*;
+        match kind {
+            1 => Some(Code),
+            2 => Some(Strings),
+            3 => Some(Numbers),
+            4 => Some(Functions),
+            5 => Some(Debug),
+            6 => Some(Syscalls),
+            _ => None,
+        }
+    }
+}
+
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+trait Buffer {
+    fn put_u16(&mut self, value: u16);
+    fn put_u32(&mut self, value: u32);
+    fn put_strings(&mut self, strings: &[String]);
+}
+
+impl Buffer for Vec<u8> {
+    fn put_strings(&mut self, strings: &[String]) {
+        self.put_u32(strings.len() as u32);
+        for str in strings {
+            self.put_u32(str.len() as u32);
+            self.extend_from_slice(str.as_bytes());
+        }
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl Asm {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sections = vec![
+            (Section::Code, self.code_section()),
+            (Section::Strings, self.string_section()),
+            (Section::Numbers, self.number_section()),
+            (Section::Functions, self.function_section()),
+        ];
+        // 没有用到系统调用时不写这一段
+        if !self.syscall_pool.is_empty() {
+            let mut data = Vec::new();
+            data.put_strings(&self.syscall_pool);
+            sections.push((Section::Syscalls, data));
+        }
+        if let Some(debug) = &self.debug {
+            sections.push((Section::Debug, debug_section(debug)));
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.put_u16(VERSION);
+        bytes.put_u16(sections.len() as u16);
+
+        let mut offset = HEADER_SIZE + ENTRY_SIZE * sections.len();
+        for (kind, data) in &sections {
+            bytes.push(*kind as u8);
+            bytes.put_u32(offset as u32);
+            bytes.put_u32(data.len() as u32);
+            offset += data.len();
+        }
+        for (_, data) in &sections {
+            bytes.extend_from_slice(data);
+        }
+
+        let sum = checksum(&bytes);
+        bytes.put_u32(sum);
+        bytes
+    }
+
+    fn code_section(&self) -> Vec<u8> {
+        self.cmds.iter().map(|cmd| cmd.0).collect()
+    }
+
+    fn string_section(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.put_strings(&self.string_pool);
+        data
+    }
+
+    fn number_section(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.put_u32(self.number_pool.len() as u32);
+        for number in &self.number_pool {
+            data.extend_from_slice(&number.0.to_le_bytes());
+        }
+        data
+    }
+
+    fn function_section(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.put_u32(self.function_pool.len() as u32);
+        for ip in &self.function_pool {
+            data.put_u32(*ip as u32);
+        }
+        data
+    }
+}
+
+// 行号表只记录位置发生变化的地方: [起始字节 | 文件 | 行 | 列], 最后是函数的标签名
+fn debug_section(debug: &DebugInfo) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.put_strings(&debug.files);
+    let mut runs = Vec::new();
+    for (index, location) in debug.lines.iter().enumerate() {
+        if index == 0 || debug.lines[index - 1] != *location {
+            runs.push((index, location));
+        }
+    }
+    data.put_u32(runs.len() as u32);
+    for (index, location) in runs {
+        data.put_u32(index as u32);
+        data.put_u32(location.file as u32);
+        data.put_u32(location.line as u32);
+        data.put_u32(location.column as u32);
+    }
+    data.put_strings(&debug.labels);
+    data
+}